@@ -1,13 +1,20 @@
-use bitcoin::{hashes::Hash, TapLeafHash, Transaction};
-use bitcoin_scriptexec::{Exec, ExecCtx, Options, Stack, TxTemplate};
+use bitcoin::secp256k1::{Keypair, Message, Secp256k1, SecretKey};
+use bitcoin::sighash::{Prevouts, SighashCache, TapSighashType};
+use bitcoin::opcodes::all::*;
+use bitcoin::script::Instruction;
+use bitcoin::{hashes::Hash, Amount, TapLeafHash, Transaction, TxIn, TxOut, XOnlyPublicKey};
+use bitcoin_scriptexec::{Exec, Stack};
+pub use bitcoin_scriptexec::{ExecCtx, Options, TxTemplate};
 
 pub use bitcoin_script::{define_pushable, script};
 define_pushable!();
 pub use bitcoin::ScriptBuf as Script;
 
+use crate::script_util::{concat_scripts, decode_script_num};
 use crate::stack::{StackData, StackTracker, StackVariable};
 
 
+#[derive(Debug)]
 pub struct StepResult {
     pub error: bool,
     pub error_msg: String,
@@ -23,55 +30,187 @@ impl StepResult {
     }
 }
 pub fn debug_script(script: bitcoin::ScriptBuf) -> (Exec, String) {
+    debug_script_with_witness(script, vec![])
+}
+
+// executes the script using the witness as the initial stack (the last element is the top of the stack)
+pub fn debug_script_with_witness(script: bitcoin::ScriptBuf, witness: Vec<Vec<u8>>) -> (Exec, String) {
+    debug_script_with_tx(script, witness, empty_tx_template())
+}
+
+// transaction without inputs used by default, any signature check fails on it
+pub fn empty_tx_template() -> TxTemplate {
+    TxTemplate {
+        tx: Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        },
+        prevouts: vec![],
+        input_idx: 0,
+        taproot_annex_scriptleaf: Some((TapLeafHash::all_zeros(), None)),
+    }
+}
+
+// transaction with a single input that can be signed with the dummy key.
+// the leaf hash is fixed, so dummy_signature() is valid for any script executed with it
+pub fn dummy_sig_tx_template() -> TxTemplate {
+    TxTemplate {
+        tx: Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![],
+        },
+        prevouts: vec![TxOut { value: Amount::ZERO, script_pubkey: bitcoin::ScriptBuf::new() }],
+        input_idx: 0,
+        taproot_annex_scriptleaf: Some((TapLeafHash::all_zeros(), None)),
+    }
+}
+
+// well known key, only meant for testing
+const DUMMY_SECRET_KEY: [u8; 32] = [1; 32];
+
+fn dummy_keypair() -> Keypair {
+    let secp = Secp256k1::new();
+    Keypair::from_secret_key(&secp, &SecretKey::from_slice(&DUMMY_SECRET_KEY).unwrap())
+}
+
+pub fn dummy_pubkey() -> XOnlyPublicKey {
+    dummy_keypair().x_only_public_key().0
+}
+
+// schnorr signature (SIGHASH_DEFAULT) of the dummy key that is valid when executing with dummy_sig_tx_template()
+pub fn dummy_signature() -> Vec<u8> {
+    let template = dummy_sig_tx_template();
+    let (leaf, _) = template.taproot_annex_scriptleaf.unwrap();
+    let sighash = SighashCache::new(&template.tx)
+        .taproot_script_spend_signature_hash(template.input_idx, &Prevouts::All(&template.prevouts), leaf, TapSighashType::Default)
+        .expect("error computing the sighash");
+    let secp = Secp256k1::new();
+    let signature = secp.sign_schnorr_no_aux_rand(&Message::from_digest(sighash.to_byte_array()), &dummy_keypair());
+    signature.serialize().to_vec()
+}
+
+pub fn debug_script_with_tx(script: bitcoin::ScriptBuf, witness: Vec<Vec<u8>>, tx: TxTemplate) -> (Exec, String) {
     let mut exec = Exec::new(
         ExecCtx::Tapscript,
         Options::default(),
-        TxTemplate {
-            tx: Transaction {
-                version: bitcoin::transaction::Version::TWO,
-                lock_time: bitcoin::locktime::absolute::LockTime::ZERO,
-                input: vec![],
-                output: vec![],
-            },
-            prevouts: vec![],
-            input_idx: 0,
-            taproot_annex_scriptleaf: Some((TapLeafHash::all_zeros(), None)),
-        },
+        tx,
         script,
-        vec![],
+        witness,
     )
     .expect("error creating exec");
 
     let mut last_opcode = String::new();
-    loop {
-        if !exec.remaining_script().is_empty() {
-            let last_opcode_new = exec.remaining_script()[0..1].to_asm_string(); 
-            if !last_opcode_new.is_empty() {
-                last_opcode = last_opcode_new;
+    while exec_next(&mut exec, &mut last_opcode) {}
+    (exec, last_opcode)
+
+}
+
+// executes the next instruction keeping track of the last opcode, returns false when the execution ends
+fn exec_next(exec: &mut Exec, last_opcode: &mut String) -> bool {
+    if !exec.remaining_script().is_empty() {
+        let last_opcode_new = exec.remaining_script()[0..1].to_asm_string();
+        if !last_opcode_new.is_empty() {
+            *last_opcode = last_opcode_new;
+        }
+    }
+    exec.exec_next().is_ok()
+}
+
+// steps whose failure is explained with the compared values
+fn is_verify_label(label: &str) -> bool {
+    label == "OP_EQUALVERIFY()" || label == "OP_NUMEQUALVERIFY()"
+}
+
+// describes the values compared by a failing verify step using the names of the model before that step
+fn explain_verify_failure(stack: &StackTracker, step: usize, compared: &[Vec<u8>]) -> String {
+    let height = if step == 0 { 0 } else { stack.history[step - 1] as usize };
+    let data = stack.data.new_from_redo_height(height);
+    let name_at = |depth: u32| {
+        let mut count = 0;
+        for var in data.stack.iter().rev() {
+            count += var.size();
+            if depth < count {
+                return data.get_name(*var).unwrap_or_default().to_string();
             }
         }
-        if exec.exec_next().is_err() {
-            break;
+        String::new()
+    };
+    format!("{} at step {} failed comparing {} ({}) with {} ({})", stack.labels[step], step,
+        name_at(1), convert_element(&compared[0]), name_at(0), convert_element(&compared[1]))
+}
+
+
+// name of the opcode as btcdeb (bitcoin core) expects it
+fn btcdeb_opcode(op: bitcoin::Opcode) -> String {
+    match op {
+        OP_PUSHBYTES_0 => "OP_0".to_string(),
+        OP_PUSHNUM_NEG1 => "OP_1NEGATE".to_string(),
+        OP_CLTV => "OP_CHECKLOCKTIMEVERIFY".to_string(),
+        OP_CSV => "OP_CHECKSEQUENCEVERIFY".to_string(),
+        _ if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) => {
+            format!("OP_{}", op.to_u8() - OP_PUSHNUM_1.to_u8() + 1)
         }
+        _ => format!("{:?}", op),
     }
-    (exec, last_opcode)
+}
 
+fn btcdeb_data(data: &[u8]) -> String {
+    if data.is_empty() {
+        "''".to_string()
+    } else {
+        format!("0x{}", hex::encode(data))
+    }
 }
 
+// command line arguments for btcdeb: the script followed by the initial stack (the last element is the top)
+pub fn btcdeb_script(script: &bitcoin::Script, witness: &[Vec<u8>]) -> String {
+    let ops = script.instructions().map(|ins| match ins {
+        Ok(Instruction::PushBytes(bytes)) if bytes.is_empty() => "OP_0".to_string(),
+        Ok(Instruction::PushBytes(bytes)) => btcdeb_data(bytes.as_bytes()),
+        Ok(Instruction::Op(op)) => btcdeb_opcode(op),
+        Err(e) => panic!("Invalid script: {:?}", e),
+    }).collect::<Vec<String>>();
 
-pub fn convert_stack(stack: &Stack) -> Vec<String> {
-    let converted = (0..stack.len()).map(|f| stack.get(f))
-        .map(|v| { if v.is_empty() { vec![0] } else { v.clone()} }).collect::<Vec<Vec<u8>>>();
+    let mut ret = format!("'[{}]'", ops.join(" "));
+    for item in witness {
+        ret.push(' ');
+        ret.push_str(&btcdeb_data(item));
+    }
+    ret
+}
+
+// the empty element (pushed by OP_0) is the number zero and is shown as 0.
+// single byte numbers are shown without padding (so nibbles read as a hex number when joined)
+// but a 0x00 byte or elements of more bytes are shown with two digits per byte
+pub fn convert_element(element: &[u8]) -> String {
+    match element {
+        [] => "0".to_string(),
+        [byte] if *byte != 0 => format!("{:x}", byte),
+        _ => hex::encode(element),
+    }
+}
 
-    let hex_strings: Vec<String> = converted.into_iter().map(|sub_vec| {
-        sub_vec.iter()
-               .map(|byte| format!("{:x}", byte)) // Convert each byte to a hex string
-               .collect::<Vec<String>>()            // Collect all hex strings into a vector
-               .join("")                            // Join all elements of the vector into a single string
-    }).collect();
+// amount of bytes of the elements that are not numbers shown by convert_element_decimal
+const BLOB_PREFIX_BYTES: usize = 4;
 
-    hex_strings
+// the elements that are numbers are shown in decimal, the rest as a blob with its length and the first bytes
+pub fn convert_element_decimal(element: &[u8]) -> String {
+    match decode_script_num(element) {
+        Some(n) => n.to_string(),
+        None => {
+            let prefix = hex::encode(&element[..element.len().min(BLOB_PREFIX_BYTES)]);
+            let ellipsis = if element.len() > BLOB_PREFIX_BYTES { "\u{2026}" } else { "" };
+            format!("<blob:{} bytes> {}{}", element.len(), prefix, ellipsis)
+        }
+    }
+}
 
+pub fn convert_stack(stack: &Stack) -> Vec<String> {
+    (0..stack.len()).map(|f| convert_element(&stack.get(f))).collect()
 }
 
 
@@ -96,20 +235,59 @@ pub fn print_execute_step(stack: &StackTracker, step_number: usize) {
 }
 
 pub fn execute_step(stack: &StackTracker, step_number: usize) -> StepResult {
+    execute_step_with_tx(stack, step_number, empty_tx_template())
+}
 
-    let script = script! {
-        for s in stack.script.iter().take(step_number+1) {
-            { s.clone() }
-        }
+pub fn execute_step_with_tx(stack: &StackTracker, step_number: usize, tx: TxTemplate) -> StepResult {
+
+    let script = concat_scripts(stack.script_chunks().take(step_number + 1));
+
+    let step_data = if stack.with_history {
+        let height = stack.history[step_number];
+        stack.data.new_from_redo_height(height as usize)
+    } else {
+        assert_eq!(step_number, stack.script.len() - 1, "Only the last step can be executed on a tracker without history (new_no_debug)");
+        stack.data.clone()
     };
 
-    let height = stack.history[step_number];
-    let step_data = stack.data.new_from_redo_height(height as usize);
+    let mut result = Exec::new(
+        ExecCtx::Tapscript,
+        Options::default(),
+        tx,
+        script,
+        stack.witness.clone(),
+    )
+    .expect("error creating exec");
 
-    let (result, last) = debug_script(script);
+    // executes step by step to know which one fails, keeping the compared values of the verify steps
+    // as the executor removes them from the stack when it fails
+    let mut last = String::new();
+    let mut failure = None;
+    let mut stopped = false;
+    'steps: for (step, s) in stack.script.iter().take(step_number + 1).enumerate() {
+        let verify = stack.with_history && stack.labels.get(step).is_some_and(|l| is_verify_label(l));
+        let compared = if verify && result.stack().len() >= 2 {
+            let len = result.stack().len();
+            Some(vec![result.stack().get(len - 2), result.stack().get(len - 1)])
+        } else {
+            None
+        };
+        for _ in s.instructions() {
+            if !exec_next(&mut result, &mut last) {
+                failure = compared.map(|c| (step, c));
+                stopped = true;
+                break 'steps;
+            }
+        }
+    }
+    // calling it again after the failure would report the next opcode as the last one
+    while !stopped && exec_next(&mut result, &mut last) {}
 
     let with_error = result.result().as_ref().unwrap().error.is_some();
-    let error = format!("{:?}", result.result().as_ref().unwrap().error);
+    let mut error = format!("{:?}", result.result().as_ref().unwrap().error);
+    if let (true, Some((step, compared))) = (with_error, failure) {
+        error = format!("{}: {}", error, explain_verify_failure(stack, step, &compared));
+    }
     let success = step_number == stack.script.len() - 1 && result.result().as_ref().unwrap().success;
 
     let converted = convert_stack(result.stack());
@@ -122,6 +300,81 @@ pub fn execute_step(stack: &StackTracker, step_number: usize) -> StepResult {
 
 }
 
+// size (in elements) of the stack and the altstack after each step, executing the script only once.
+// the steps after the first error are not included
+pub fn execute_step_sizes(stack: &StackTracker) -> Vec<(u32, u32)> {
+    let mut exec = Exec::new(
+        ExecCtx::Tapscript,
+        Options::default(),
+        empty_tx_template(),
+        stack.get_script(),
+        stack.witness.clone(),
+    )
+    .expect("error creating exec");
+
+    let mut ret = Vec::new();
+    for s in stack.script.iter() {
+        for _ in s.instructions() {
+            if exec.exec_next().is_err() {
+                return ret;
+            }
+        }
+        ret.push((exec.stack().len() as u32, exec.altstack().len() as u32));
+    }
+    ret
+}
+
+// executes the whole script with the transaction, context and options given, returning the state after each opcode.
+// the elements are not grouped in variables as the model only knows the stack between steps.
+// the last entry has the result of the execution
+pub fn execute_trace(stack: &StackTracker, tx: TxTemplate, ctx: ExecCtx, options: Options) -> Vec<StepResult> {
+    let mut exec = Exec::new(ctx, options, tx, stack.get_script(), stack.witness.clone())
+        .expect("error creating exec");
+
+    let mut ret = Vec::new();
+    let mut last = String::new();
+    loop {
+        // once the script is consumed the call only sets the result
+        let executes = !exec.remaining_script().is_empty();
+        let more = exec_next(&mut exec, &mut last);
+        let (error, error_msg, success) = match exec.result() {
+            Some(result) => (result.error.is_some(), format!("{:?}", result.error), result.success),
+            None => (false, String::new(), false),
+        };
+        let entry = StepResult::new(error, error_msg, success, last.clone(), convert_stack(exec.stack()), convert_stack(exec.altstack()));
+        match ret.last_mut() {
+            Some(prev) if !executes => *prev = entry,
+            _ => ret.push(entry),
+        }
+        if !more {
+            return ret;
+        }
+    }
+}
+
+// executes only the steps from..=to using `initial` as the stack before the step `from`.
+// the altstack is expected to be empty at the step `from`
+pub fn execute_window(stack: &StackTracker, from: usize, to: usize, initial: &[Vec<u8>]) -> StepResult {
+    let script = stack.get_script_range(from, to);
+
+    let height = stack.history[to];
+    let step_data = stack.data.new_from_redo_height(height as usize);
+
+    let (result, last) = debug_script_with_witness(script, initial.to_vec());
+
+    let with_error = result.result().as_ref().unwrap().error.is_some();
+    let error = format!("{:?}", result.result().as_ref().unwrap().error);
+    let success = result.result().as_ref().unwrap().success;
+
+    let converted = convert_stack(result.stack());
+    let stack = show_stacks(&step_data, &step_data.stack, converted, false);
+
+    let converted = convert_stack(result.altstack());
+    let altstack = show_stacks(&step_data, &step_data.altstack, converted, true);
+
+    StepResult::new(with_error, error, success, last, stack, altstack)
+}
+
 pub fn show_stacks(data: &StackData, stack: &[StackVariable], mut real: Vec<String>, reverse: bool) -> Vec<String> {
     let iter : Box<dyn Iterator<Item=&StackVariable>> = if reverse {
         Box::new(stack.iter().rev())
@@ -140,7 +393,8 @@ pub fn show_stacks(data: &StackData, stack: &[StackVariable], mut real: Vec<Stri
             real_sub = real.iter().take(var.size() as usize).cloned().collect();
             real.drain(0..var.size() as usize);
         }
-        ret.push(format!("{} {}", data_item, real_sub).to_string());
+        let held = if data.is_held(*var) { "held | " } else { "" };
+        ret.push(format!("{}{} {}", data_item, held, real_sub).to_string());
     }
     ret
 } 
@@ -158,3 +412,90 @@ pub fn show_altstack(data: &StackData, real: Vec<String> ) {
         println!("{}", s);
     }
 }
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_element() {
+        assert_eq!(convert_element(&[]), "0");
+        assert_eq!(convert_element(&[0]), "00");
+        assert_eq!(convert_element(&[0xa]), "a");
+        assert_eq!(convert_element(&[0x12]), "12");
+        assert_eq!(convert_element(&[0x1, 0x2]), "0102");
+
+        let mut stack = StackTracker::new();
+        stack.number(0);
+        stack.hexstr("00");
+        stack.number(10);
+        let ret = execute_step(&stack, stack.get_script_len() - 1);
+        assert!(ret.stack[0].ends_with(" 0"));
+        assert!(ret.stack[1].ends_with(" 00"));
+        assert!(ret.stack[2].ends_with(" a"));
+    }
+
+    #[test]
+    fn test_convert_element_decimal() {
+        assert_eq!(convert_element_decimal(&[]), "0");
+        assert_eq!(convert_element_decimal(&[0x12]), "18");
+        assert_eq!(convert_element_decimal(&[0x81]), "-1");
+        assert_eq!(convert_element_decimal(&[0xff, 0xff, 0xff, 0x7f]), "2147483647");
+        // not minimally encoded
+        assert_eq!(convert_element_decimal(&[0]), "<blob:1 bytes> 00");
+        let hash = (0..32).collect::<Vec<u8>>();
+        assert_eq!(convert_element_decimal(&hash), "<blob:32 bytes> 00010203\u{2026}");
+    }
+
+    #[test]
+    fn test_show_held() {
+        let mut stack = StackTracker::new();
+        let x = stack.number(5);
+        stack.hold(x);
+        let y = stack.number(7);
+        stack.hold(y);
+        stack.drop(y);
+        stack.number(9);
+        let ret = execute_step(&stack, stack.get_script_len() - 1);
+        assert_eq!(ret.stack.len(), 2);
+        assert!(ret.stack[0].contains("| held | "));
+        assert!(!ret.stack[1].contains("held"));
+        assert!(!stack.data.is_held(y));
+
+        // the earlier steps keep showing it after releasing it
+        stack.release(x);
+        let ret = execute_step(&stack, 0);
+        assert!(ret.stack[0].contains("| held | "));
+        let ret = execute_step(&stack, stack.get_script_len() - 1);
+        assert!(!ret.stack[0].contains("held"));
+    }
+
+    #[test]
+    fn test_execute_window() {
+        let mut stack = StackTracker::new();
+        stack.number(5);
+        stack.number(7);
+        stack.op_add();
+        let from = stack.get_script_len();
+        stack.number(12);
+        stack.op_equalverify();
+        stack.number(3);
+        let to = stack.get_script_len() - 1;
+
+        // capture the stack before the window
+        let (exec, _) = debug_script(stack.get_script_range(0, from - 1));
+        let initial = (0..exec.stack().len()).map(|i| exec.stack().get(i)).collect::<Vec<Vec<u8>>>();
+        assert_eq!(initial, vec![vec![12]]);
+
+        let ret = execute_window(&stack, from, to, &initial);
+        assert!(!ret.error);
+        assert!(ret.success);
+        assert_eq!(ret.stack.len(), 1);
+
+        let ret = execute_window(&stack, from, to, &[vec![11]]);
+        assert!(ret.error);
+        assert!(!ret.success);
+    }
+}
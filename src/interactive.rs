@@ -1,6 +1,7 @@
 
 use std::io::Stdout; 
 use std::io::stdout;
+use std::io::{stdin, BufRead, IsTerminal};
 
 use crossterm::style::SetBackgroundColor;
 use crossterm::terminal::disable_raw_mode;
@@ -15,7 +16,7 @@ use crossterm::{
     ExecutableCommand,
 };
 
-use crate::debugger::execute_step;
+use crate::debugger::{execute_step, print_execute_step};
 use crate::stack::StackTracker;
 
 fn show_command(stdout: &mut Stdout, command: &str, help: &str ) {
@@ -76,6 +77,8 @@ fn show_step(stdout : &mut Stdout, stack: &StackTracker, step: usize, bp_name: &
     execute!(stdout, SetForegroundColor(Color::DarkGreen), Print("Interactive mode. ")).unwrap();
     show_command(stdout, "n", ": next bp | ");
     show_command(stdout, "p", ": previous bp | ");
+    show_command(stdout, "]", ": next section | ");
+    show_command(stdout, "[", ": previous section | ");
     execute!( stdout, SetForegroundColor(Color::DarkGreen), Print("Step commands: "), ResetColor).unwrap();
     show_command(stdout, "<-", " (-1) | ");
     show_command(stdout, "->", " (+1) | ");
@@ -92,6 +95,10 @@ fn show_step(stdout : &mut Stdout, stack: &StackTracker, step: usize, bp_name: &
                 Print(step),
                 SetForegroundColor(Color::Blue), Print(" BP: "), ResetColor,
                 Print(bp_name),
+                SetForegroundColor(Color::Blue), Print(" Section: "), ResetColor,
+                Print(stack.get_section(step as u32).unwrap_or("")),
+                SetForegroundColor(Color::Blue), Print(" Op: "), ResetColor,
+                Print(stack.step_label(step).unwrap_or("")),
             ).unwrap();
 
     let res = execute_step(stack, step);
@@ -137,7 +144,97 @@ fn show_step(stdout : &mut Stdout, stack: &StackTracker, step: usize, bp_name: &
 }
 
 
+#[derive(Debug, PartialEq, Eq)]
+enum LineCommand {
+    NextBreakpoint,
+    PrevBreakpoint,
+    NextSection,
+    PrevSection,
+    Move(i32),
+    Goto(i32),
+    Quit,
+}
+
+fn parse_line_command(line: &str) -> Option<LineCommand> {
+    let line = line.trim();
+    match line {
+        "" => Some(LineCommand::Move(1)),
+        "n" => Some(LineCommand::NextBreakpoint),
+        "p" => Some(LineCommand::PrevBreakpoint),
+        "]" => Some(LineCommand::NextSection),
+        "[" => Some(LineCommand::PrevSection),
+        "q" => Some(LineCommand::Quit),
+        _ => {
+            if let Some(n) = line.strip_prefix('+') {
+                n.parse().ok().map(LineCommand::Move)
+            } else if let Some(n) = line.strip_prefix('-') {
+                n.parse::<i32>().ok().map(|n| LineCommand::Move(-n))
+            } else {
+                line.parse().ok().map(LineCommand::Goto)
+            }
+        }
+    }
+}
+
+// returns the new step or None if the user wants to exit
+fn apply_line_command(stack: &StackTracker, command: LineCommand, step: i32, bp_name: &mut String) -> Option<i32> {
+    let max_step = stack.get_script_len() as i32 - 1;
+    let step = match command {
+        LineCommand::Quit => return None,
+        LineCommand::NextBreakpoint | LineCommand::PrevBreakpoint => {
+            let bp = if command == LineCommand::NextBreakpoint {
+                stack.get_next_breakpoint(step as u32)
+            } else {
+                stack.get_prev_breakpoint(step as u32)
+            };
+            match bp {
+                Some((pos, name)) => {
+                    *bp_name = name;
+                    pos as i32
+                }
+                None => step,
+            }
+        }
+        LineCommand::NextSection => stack.get_next_comment(step as u32).map(|(pos, _)| pos as i32).unwrap_or(step),
+        LineCommand::PrevSection => stack.get_prev_comment(step as u32).map(|(pos, _)| pos as i32).unwrap_or(step),
+        LineCommand::Move(change) => step + change,
+        LineCommand::Goto(step) => step,
+    };
+    // a tracker without steps stays at step 0 (clamp panics when max < min)
+    Some(step.clamp(0, max_step.max(0)))
+}
+
+// line based stepping used when there is no terminal available (pipes, CI, log capture)
+pub fn interactive_line(stack: &StackTracker) {
+    println!("Line mode. <enter>: next step | +N / -N: move N steps | N: go to step N | n: next bp | p: previous bp | ]: next section | [: previous section | q: exit");
+
+    let mut step : i32 = 0;
+    let mut bp_name = "start".to_string();
+    let mut lines = stdin().lock().lines();
+    loop {
+        println!("Step: {} BP: {} Section: {} Op: {}", step, bp_name, stack.get_section(step as u32).unwrap_or(""), stack.step_label(step as usize).unwrap_or(""));
+        print_execute_step(stack, step as usize);
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        match parse_line_command(&line) {
+            Some(command) => match apply_line_command(stack, command, step, &mut bp_name) {
+                Some(new_step) => step = new_step,
+                None => break,
+            },
+            None => println!("Unknown command: {}", line.trim()),
+        }
+    }
+}
+
 pub fn interactive(stack: &StackTracker) {
+    if !stdout().is_terminal() || !stdin().is_terminal() {
+        interactive_line(stack);
+        return;
+    }
+
     let mut stdout = stdout();
 
     enable_raw_mode().expect("Failed to enable raw mode");
@@ -177,6 +274,16 @@ pub fn interactive(stack: &StackTracker) {
                     bp_name = x.as_ref().unwrap().1.to_string();
                 }
             }
+            if key_event.code == KeyCode::Char(']') {
+                if let Some((pos, _)) = stack.get_next_comment(step as u32) {
+                    step = pos as i32;
+                }
+            }
+            if key_event.code == KeyCode::Char('[') {
+                if let Some((pos, _)) = stack.get_prev_comment(step as u32) {
+                    step = pos as i32;
+                }
+            }
             if key_event.code == KeyCode::Char('t') {
                 trim = !trim;
             }
@@ -229,3 +336,48 @@ pub fn interactive(stack: &StackTracker) {
 }
 
 
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line_command() {
+        assert_eq!(parse_line_command(""), Some(LineCommand::Move(1)));
+        assert_eq!(parse_line_command("n\n"), Some(LineCommand::NextBreakpoint));
+        assert_eq!(parse_line_command("p"), Some(LineCommand::PrevBreakpoint));
+        assert_eq!(parse_line_command("]"), Some(LineCommand::NextSection));
+        assert_eq!(parse_line_command("["), Some(LineCommand::PrevSection));
+        assert_eq!(parse_line_command(" q "), Some(LineCommand::Quit));
+        assert_eq!(parse_line_command("+10"), Some(LineCommand::Move(10)));
+        assert_eq!(parse_line_command("-3"), Some(LineCommand::Move(-3)));
+        assert_eq!(parse_line_command("42"), Some(LineCommand::Goto(42)));
+        assert_eq!(parse_line_command("x"), None);
+    }
+
+    #[test]
+    fn test_apply_line_command() {
+        let mut stack = StackTracker::new();
+        stack.comment("section");
+        stack.number(1);
+        stack.number(2);
+        stack.set_breakpoint("bp");
+        stack.op_add();
+
+        let mut bp_name = String::new();
+        assert_eq!(apply_line_command(&stack, LineCommand::Move(1), 0, &mut bp_name), Some(1));
+        assert_eq!(apply_line_command(&stack, LineCommand::Move(-5), 1, &mut bp_name), Some(0));
+        assert_eq!(apply_line_command(&stack, LineCommand::Goto(100), 0, &mut bp_name), Some(4));
+        assert_eq!(apply_line_command(&stack, LineCommand::PrevSection, 3, &mut bp_name), Some(0));
+        assert_eq!(apply_line_command(&stack, LineCommand::NextSection, 0, &mut bp_name), Some(0));
+        assert_eq!(apply_line_command(&stack, LineCommand::NextBreakpoint, 0, &mut bp_name), Some(3));
+        assert_eq!(bp_name, "bp");
+        assert_eq!(apply_line_command(&stack, LineCommand::Quit, 0, &mut bp_name), None);
+
+        let empty = StackTracker::new();
+        assert_eq!(apply_line_command(&empty, LineCommand::Move(1), 0, &mut bp_name), Some(0));
+        assert_eq!(apply_line_command(&empty, LineCommand::Goto(3), 0, &mut bp_name), Some(0));
+    }
+}
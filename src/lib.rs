@@ -1,6 +1,12 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+extern crate alloc;
+
 pub mod stack;
+#[cfg(feature = "std")]
 pub mod debugger;
 pub mod script_util;
 #[cfg(feature = "interactive")]
 pub mod interactive;
-pub mod optimizer;
\ No newline at end of file
+pub mod optimizer;
+#[cfg(test)]
+mod test_util;
\ No newline at end of file
@@ -1,3 +1,5 @@
+use alloc::vec;
+use alloc::vec::Vec;
 use bitcoin::{script::Instruction, Opcode};
 pub use bitcoin_script::{define_pushable, script};
 define_pushable!();
@@ -11,8 +13,9 @@ fn to_vec(script: &Script) -> Vec<Instruction<'_>> {
             Ok(x) => {
                 instructions.push(x);
             }
-            Err(e) => {
-                println!("{:?}", e);
+            Err(_e) => {
+                #[cfg(feature = "std")]
+                println!("{:?}", _e);
             }
         }
     }
@@ -105,6 +108,9 @@ fn replace(instructions: &mut Vec<Instruction>, mut i: usize, count: usize) -> u
 pub fn opcode_transformation( opcode: &Opcode, previous_opcode: Option<Opcode>, previous_digit: Option<u8>) -> Option<Option<Opcode>> {
     match (opcode, previous_opcode, previous_digit) {
         (&OP_FROMALTSTACK, Some(OP_TOALTSTACK), None) => Some(None),
+        (&OP_TOALTSTACK, Some(OP_FROMALTSTACK), None) => Some(None),
+        (&OP_VERIFY, Some(OP_EQUAL), None) => Some(Some(OP_EQUALVERIFY)),
+        (&OP_VERIFY, Some(OP_NUMEQUAL), None) => Some(Some(OP_NUMEQUALVERIFY)),
         (&OP_PICK, None, Some(0)) => Some(Some(OP_DUP)),
         (&OP_PICK, None, Some(1)) => Some(Some(OP_OVER)),
         (&OP_ROLL, None, Some(0)) => Some(None),
@@ -161,7 +167,7 @@ mod tests {
     define_pushable!();
     use crate::stack::StackTracker;
 
-    use crate::debugger::debug_script;
+    use crate::debugger::{convert_stack, debug_script, debug_script_with_witness};
     use crate::script_util::*;
 
     use super::*;
@@ -238,6 +244,49 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_from_to_alt() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.to_altstack();
+        stack.number(2);
+        stack.from_altstack();
+        stack.to_altstack();
+        stack.op_drop();
+        stack.from_altstack();
+
+        let mut stack2 = StackTracker::new();
+        stack2.number(1);
+        stack2.to_altstack();
+        stack2.number(2);
+        stack2.op_drop();
+        stack2.from_altstack();
+
+        let optimized = optimize(stack.get_script());
+        assert_eq!(optimized, stack2.get_script());
+        let ret = debug_script(optimized);
+        assert!(ret.0.result().unwrap().success);
+    }
+
+    #[test]
+    fn test_equal_verify() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.number(1);
+        stack.op_equal();
+        stack.op_verify();
+        stack.number(2);
+        stack.number(2);
+        stack.op_numequal();
+        stack.op_verify();
+        stack.op_true();
+
+        let optimized = optimize(stack.get_script());
+        assert_eq!(optimized, script!{ 1 1 OP_EQUALVERIFY 2 2 OP_NUMEQUALVERIFY OP_TRUE });
+        let ret = debug_script(optimized);
+        assert!(ret.0.result().unwrap().success);
+    }
+
     #[test]
     fn test_pick_0() {
         let mut stack = StackTracker::new();
@@ -345,4 +394,106 @@ mod tests {
         assert_eq!(script.as_script().to_asm_string(), new_script.as_script().to_asm_string());
     }
 
+    // xorshift64, enough to generate reproducible scripts from a seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    // random script of small number pushes (with long runs of the same number) mixed with other opcodes.
+    // the depth of both stacks is tracked so the script never fails before the end, as the optimizer
+    // only needs to keep the behavior of scripts that don't fail (i.e. OP_TOALTSTACK OP_FROMALTSTACK is removed
+    // even if the stack is empty)
+    fn random_script(rng: &mut Rng, mut depth: u64) -> Script {
+        let mut builder = bitcoin::script::Builder::new();
+        let mut altdepth = 0;
+        for _ in 0..60 {
+            match rng.below(12) {
+                0..=3 => {
+                    let n = rng.below(17) as i64;
+                    let count = 1 + rng.below(20);
+                    for _ in 0..count {
+                        builder = builder.push_int(n);
+                    }
+                    depth += count;
+                }
+                4 if depth > 0 => {
+                    let n = rng.below(depth.min(17));
+                    let op = if rng.below(2) == 0 { OP_PICK } else { OP_ROLL };
+                    builder = builder.push_int(n as i64).push_opcode(op);
+                    if op == OP_PICK {
+                        depth += 1;
+                    }
+                }
+                5 if depth > 0 => {
+                    builder = builder.push_opcode(OP_DUP).push_opcode(OP_DUP);
+                    let op = if rng.below(2) == 0 { OP_EQUAL } else { OP_NUMEQUAL };
+                    builder = builder.push_opcode(op).push_opcode(OP_VERIFY);
+                }
+                6 if depth > 0 => {
+                    builder = builder.push_opcode(OP_TOALTSTACK).push_opcode(OP_FROMALTSTACK);
+                }
+                7 if depth > 0 => {
+                    builder = builder.push_opcode(OP_TOALTSTACK);
+                    depth -= 1;
+                    altdepth += 1;
+                }
+                8 if altdepth > 0 => {
+                    builder = builder.push_opcode(OP_FROMALTSTACK);
+                    depth += 1;
+                    altdepth -= 1;
+                }
+                9 if depth >= 3 => {
+                    let ops = [OP_DUP, OP_DROP, OP_SWAP, OP_OVER, OP_ROT, OP_NIP, OP_2DUP, OP_3DUP, OP_1ADD, OP_DEPTH];
+                    let op = ops[rng.below(ops.len() as u64) as usize];
+                    builder = builder.push_opcode(op);
+                    depth = match op {
+                        OP_DROP | OP_NIP => depth - 1,
+                        OP_DUP | OP_OVER | OP_DEPTH => depth + 1,
+                        OP_2DUP => depth + 2,
+                        OP_3DUP => depth + 3,
+                        _ => depth,
+                    };
+                }
+                10 if depth >= 2 => {
+                    let op = if rng.below(2) == 0 { OP_ADD } else { OP_EQUAL };
+                    builder = builder.push_opcode(op);
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+        builder.into_script()
+    }
+
+    #[test]
+    fn test_optimize_random_scripts() {
+        for seed in 1..=500u64 {
+            let mut rng = Rng(seed);
+            let witness = (0..rng.below(8)).map(|_| match rng.below(17) {
+                0 => vec![],
+                v => vec![v as u8],
+            }).collect::<Vec<Vec<u8>>>();
+            let script = random_script(&mut rng, witness.len() as u64);
+            let optimized = optimize(script.clone());
+
+            let (original, _) = debug_script_with_witness(script.clone(), witness.clone());
+            let (result, _) = debug_script_with_witness(optimized.clone(), witness);
+            let context = format!("seed {}\nscript: {}\noptimized: {}", seed, script.to_asm_string(), optimized.to_asm_string());
+            assert_eq!(convert_stack(original.stack()), convert_stack(result.stack()), "{}", context);
+            assert_eq!(convert_stack(original.altstack()), convert_stack(result.altstack()), "{}", context);
+            assert_eq!(original.result().unwrap().success, result.result().unwrap().success, "{}", context);
+        }
+    }
+
 }
\ No newline at end of file
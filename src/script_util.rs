@@ -1,7 +1,158 @@
+use alloc::vec::Vec;
+use bitcoin::opcodes::all::*;
+use bitcoin::opcodes::{Class, ClassifyContext};
+use bitcoin::Opcode;
+use bitcoin::script::Instruction;
+use core::fmt;
+
 pub use bitcoin_script::{define_pushable, script};
 define_pushable!();
 pub use bitcoin::ScriptBuf as Script;
 
+// Representation used to split numbers into stack elements
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Base {
+    #[default]
+    Nibble,
+    Byte,
+}
+
+impl Base {
+    pub fn bits(&self) -> u32 {
+        match self {
+            Base::Nibble => 4,
+            Base::Byte => 8,
+        }
+    }
+
+    // amount of stack elements needed to represent a number of `bits` bits
+    pub fn elements(&self, bits: u32) -> u32 {
+        bits / self.bits()
+    }
+}
+
+// minimal script number encoding (little endian, sign in the highest bit of the last byte)
+pub fn encode_number(n: i64) -> Vec<u8> {
+    let mut ret = Vec::new();
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        ret.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if let Some(last) = ret.last_mut() {
+        if *last & 0x80 != 0 {
+            ret.push(if n < 0 { 0x80 } else { 0 });
+        } else if n < 0 {
+            *last |= 0x80;
+        }
+    }
+    ret
+}
+
+// number pushed by the instruction (if it is a push of a valid number operand, see decode_script_num)
+pub fn pushed_number(instruction: &Instruction) -> Option<i64> {
+    match instruction {
+        Instruction::PushBytes(bytes) => decode_script_num(bytes.as_bytes()),
+        Instruction::Op(op) if *op == OP_PUSHNUM_NEG1 => Some(-1),
+        Instruction::Op(op) if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) => {
+            Some((op.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as i64)
+        }
+        _ => None,
+    }
+}
+
+// depths rolled by the script (OP_SWAP and OP_ROT count as rolls of depth 1 and 2).
+// OP_ROLL with a depth computed at runtime are not included
+pub fn roll_depths(script: &Script) -> Vec<u32> {
+    let mut ret = Vec::new();
+    let mut last_number = None;
+    for instruction in script.instructions().flatten() {
+        let depth = match instruction {
+            Instruction::Op(OP_ROLL) => last_number.filter(|n| *n >= 0).map(|n| n as u32),
+            Instruction::Op(OP_SWAP) => Some(1),
+            Instruction::Op(OP_ROT) => Some(2),
+            _ => None,
+        };
+        if let Some(depth) = depth {
+            ret.push(depth);
+        }
+        last_number = pushed_number(&instruction);
+    }
+    ret
+}
+
+// depths used by the OP_PICK / OP_ROLL of the script that are preceded by a number (OP_SWAP and OP_ROT not included)
+pub fn pushed_depths(script: &Script, opcode: Opcode) -> Vec<u32> {
+    let mut ret = Vec::new();
+    let mut last_number = None;
+    for instruction in script.instructions().flatten() {
+        if instruction == Instruction::Op(opcode) {
+            if let Some(depth) = last_number.filter(|n| *n >= 0) {
+                ret.push(depth as u32);
+            }
+        }
+        last_number = pushed_number(&instruction);
+    }
+    ret
+}
+
+// opcodes of the script that can not be used in tapscript: the ones that are always invalid, the disabled ones
+// (that are OP_SUCCESS in tapscript, including OP_CAT as BIP-347 is not active) and OP_CHECKMULTISIG(VERIFY).
+// each opcode is reported once, in order of appearance
+pub fn invalid_tapscript_opcodes(script: &Script) -> Vec<Opcode> {
+    let mut ret = Vec::new();
+    for instruction in script.instructions().flatten() {
+        if let Instruction::Op(op) = instruction {
+            let invalid = match op.classify(ClassifyContext::TapScript) {
+                Class::IllegalOp | Class::SuccessOp => true,
+                Class::ReturnOp => op != OP_RETURN,
+                _ => false,
+            };
+            if invalid && !ret.contains(&op) {
+                ret.push(op);
+            }
+        }
+    }
+    ret
+}
+
+// decodes a script number of up to 8 bytes (the widest that fits an i64). None for longer elements
+pub fn decode_number(bytes: &[u8]) -> Option<i64> {
+    if bytes.len() > 8 {
+        return None;
+    }
+    let Some(last) = bytes.last() else { return Some(0) };
+    let mut ret: i64 = 0;
+    for (i, b) in bytes.iter().enumerate() {
+        let b = if i == bytes.len() - 1 { b & 0x7f } else { *b };
+        ret |= (b as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        Some(-ret)
+    } else {
+        Some(ret)
+    }
+}
+
+// the element as a number operand: at most 4 bytes and minimally encoded. None for any other element (hashes, keys...)
+pub fn decode_script_num(bytes: &[u8]) -> Option<i64> {
+    if bytes.len() > 4 {
+        return None;
+    }
+    let n = decode_number(bytes)?;
+    (encode_number(n) == bytes).then_some(n)
+}
+
+// verifies that the number on top of the stack takes at most 4 bytes
+pub fn check_number_size() -> Script {
+    script! {
+        OP_SIZE
+        4
+        OP_LESSTHANOREQUAL
+        OP_VERIFY
+    }
+}
+
 pub fn move_from(address: u32, size: u32) -> Script {
     script! {
         for _ in 0..size {
@@ -11,6 +162,46 @@ pub fn move_from(address: u32, size: u32) -> Script {
     }
 }
 
+// moves the element at `depth` to the top of the stack using the cheapest opcode
+pub fn roll(depth: u32) -> Script {
+    match depth {
+        0 => script! {},
+        1 => script! { OP_SWAP },
+        2 => script! { OP_ROT },
+        _ => script! { { depth } OP_ROLL },
+    }
+}
+
+// reorders the top elements of the stack with the minimum amount of rolls.
+// permutation[i] is the depth that had before the script the element that ends at depth i.
+// the deepest elements of the result that keep their original order are left in place,
+// the rest are rolled to the top from the deepest to the topmost
+pub fn permute(permutation: &[usize]) -> Script {
+    let k = permutation.len();
+    let mut sorted = permutation.to_vec();
+    sorted.sort();
+    assert!(sorted.iter().enumerate().all(|(i, p)| i == *p), "{:?} is not a valid permutation", permutation);
+
+    let mut keep = 1.min(k);
+    while keep < k && permutation[k - 1 - keep] < permutation[k - keep] {
+        keep += 1;
+    }
+
+    // original depth of the element that is at each depth
+    let mut current = (0..k).collect::<Vec<usize>>();
+    let mut ret = script! {};
+    for target in permutation[..k - keep].iter().rev() {
+        let depth = current.iter().position(|x| x == target).unwrap();
+        current.remove(depth);
+        current.insert(0, *target);
+        ret = script! {
+            { ret }
+            { roll(depth as u32) }
+        };
+    }
+    ret
+}
+
 pub fn copy_from(address: u32, size: u32) -> Script {
     script! {
         for _ in 0..size {
@@ -20,6 +211,97 @@ pub fn copy_from(address: u32, size: u32) -> Script {
     }
 }
 
+// selects the element `from + index` of a table using a binary tree of OP_IF.
+// expects the index on top of the stack and the top of the table `table_offset` elements below it
+pub fn select_if_tree(from: u32, count: u32, table_offset: u32) -> Script {
+    if count == 1 {
+        return script! {
+            OP_DROP
+            { copy_from(table_offset + from, 1) }
+        };
+    }
+    let half = count / 2;
+    script! {
+        OP_DUP
+        { from + half }
+        OP_LESSTHAN
+        OP_IF
+            { select_if_tree(from, half, table_offset) }
+        OP_ELSE
+            { select_if_tree(from + half, count - half, table_offset) }
+        OP_ENDIF
+    }
+}
+
+// two's complement negation of the number made of the `size` elements on top of the stack (most significant deepest).
+// subtracts it from zero element by element starting from the least significant one, keeping the borrow on top
+// and the result digits on the altstack
+pub fn negate_base(size: u32, base: Base) -> Script {
+    let radix = 1_u32 << base.bits();
+    script! {
+        0
+        for _ in 0..size {
+            OP_ADD
+            // tapscript needs the condition of OP_IF to be 0 or 1
+            OP_DUP
+            OP_0NOTEQUAL
+            OP_IF
+                { radix }
+                OP_SWAP
+                OP_SUB
+                1
+            OP_ELSE
+                0
+            OP_ENDIF
+            OP_SWAP
+            OP_TOALTSTACK
+        }
+        OP_DROP
+        for _ in 0..size {
+            OP_FROMALTSTACK
+        }
+    }
+}
+
+// splits the number on top of the stack in `bits` bits leaving the most significant on top.
+// fails if the number is negative or does not fit in `bits` bits
+pub fn number_to_bits(bits: u32) -> Script {
+    script! {
+        for i in (0..bits).rev() {
+            OP_DUP { 1_i64 << i } OP_GREATERTHANOREQUAL
+            OP_DUP OP_TOALTSTACK
+            OP_IF { 1_i64 << i } OP_SUB OP_ENDIF
+        }
+        OP_NOT OP_VERIFY
+        for _ in 0..bits {
+            OP_FROMALTSTACK
+        }
+    }
+}
+
+// multiplies the number on top of the stack by the number split with number_to_bits (below it) using doubling.
+// the bits are kept and the number is replaced by the product
+pub fn mul_by_bits(bits: u32) -> Script {
+    script! {
+        OP_0
+        for i in (0..bits).rev() {
+            OP_DUP OP_ADD
+            { 2 + bits - 1 - i } OP_PICK
+            OP_IF OP_OVER OP_ADD OP_ENDIF
+        }
+        OP_NIP
+    }
+}
+
+// concatenates the chunks into a single script copying each one only once
+pub fn concat_scripts<'a>(chunks: impl Iterator<Item = &'a Script>) -> Script {
+    let mut bytes = Vec::new();
+    for chunk in chunks {
+        bytes.extend_from_slice(chunk.as_bytes());
+    }
+    Script::from_bytes(bytes)
+}
+
 pub fn drop_count(n: u32) -> Script {
     script! {
         for _ in 0..n / 2 {
@@ -64,6 +346,7 @@ pub fn number_16_to_nibble(n: u16) -> Script {
     }
 }
 
+// always pushes 8 nibbles (most significant first), use try_number_to_nibble for other widths
 pub fn number_to_nibble(n: u32) -> Script { 
     script! {
        for i in (0..8).rev() { 
@@ -72,6 +355,38 @@ pub fn number_to_nibble(n: u32) -> Script {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NibbleOverflow {
+    pub value: u64,
+    pub width: u32,
+}
+
+impl fmt::Display for NibbleOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The value {:#x} does not fit in {} nibbles", self.value, self.width)
+    }
+}
+
+// pushes `width` nibbles (most significant first) failing if the value needs more of them
+pub fn try_number_to_nibble(n: u64, width: u32) -> Result<Script, NibbleOverflow> {
+    if width < 16 && n >> (width * 4) != 0 {
+        return Err(NibbleOverflow { value: n, width });
+    }
+    Ok(script! {
+       for i in (0..width).rev() {
+            { if i < 16 { ((n >> (i * 4)) & 0xF) as u32 } else { 0 } }
+        }
+    })
+}
+
+pub fn number_to_base(n: u32, bits: u32, base: Base) -> Script {
+    let mask = (1 << base.bits()) - 1;
+    script! {
+       for i in (0..base.elements(bits)).rev() {
+            { (n >> (i * base.bits())) & mask }
+        }
+    }
+}
 
 pub fn byte_to_nibble(n: u8) -> Script { 
     script! {
@@ -91,6 +406,21 @@ pub fn verify_n(n: u32) -> Script {
     }
 }
 
+// same as verify_n but using OP_ROT for depth 2 and avoiding the roll of the last pair
+pub fn equalverify_n(n: u32) -> Script {
+    script! {
+        for i in 0..n {
+            if n - i == 2 {
+                OP_ROT
+            } else if n - i > 2 {
+                { n - i }
+                OP_ROLL
+            }
+            OP_EQUALVERIFY
+        }
+    }
+}
+
 pub fn reverse_u32() -> Script {
     script! {
         OP_SWAP
@@ -104,4 +434,39 @@ pub fn reverse_u32() -> Script {
         7
         OP_ROLL
     }
+}
+
+pub fn reverse_u32_bytes() -> Script {
+    script! {
+        OP_SWAP
+        OP_2SWAP
+        OP_SWAP
+    }
+}
+
+// leaves the min and max of the top two elements with the max on top
+pub fn compare_exchange() -> Script {
+    script! {
+        OP_2DUP
+        OP_MAX
+        OP_TOALTSTACK
+        OP_MIN
+        OP_FROMALTSTACK
+    }
+}
+
+// sorts the top n elements leaving the biggest on top.
+// each pass moves the max of the remaining elements to the altstack
+pub fn sort_top(n: u32) -> Script {
+    script! {
+        for k in (2..=n).rev() {
+            { compare_exchange() }
+            for j in 2..k {
+                { move_from(j, 1) }
+                { compare_exchange() }
+            }
+            OP_TOALTSTACK
+        }
+        { fromaltstack(n.saturating_sub(1)) }
+    }
 }
\ No newline at end of file
@@ -1,17 +1,34 @@
-use std::collections::HashMap;
-
-use bitcoin::{opcodes::OP_TRUE, Opcode};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use bitcoin::taproot::TAPROOT_CONTROL_BASE_SIZE;
+use bitcoin::{opcodes::OP_TRUE, Opcode, Witness};
 use bitcoin::opcodes::all::*;
 
 pub use bitcoin_script::{define_pushable, script};
 define_pushable!();
 pub use bitcoin::ScriptBuf as Script;
 
-use crate::debugger::{execute_step, print_execute_step, show_altstack, show_stack, StepResult};
+#[cfg(feature = "std")]
+use crate::debugger::{btcdeb_script, debug_script_with_witness, dummy_sig_tx_template, execute_step, execute_step_sizes, execute_step_with_tx, execute_trace, print_execute_step, show_altstack, show_stack, ExecCtx, Options, StepResult, TxTemplate};
 use super::script_util::*;
+use crate::optimizer::optimize;
 
 use hex::FromHex;
 
+// default max size of the tables that can be accessed with select_from_table_if (see set_max_table_if_size)
+pub const MAX_TABLE_IF_SIZE: u32 = 16;
+// max amount of elements that can be sorted with sort_top
+pub const MAX_SORT_SIZE: u32 = 8;
+// bits of the x used by horner_eval, x needs to be in [0, 2^HORNER_X_BITS)
+pub const HORNER_X_BITS: u32 = 16;
+// rolls deeper than this are reported by lint
+pub const LINT_MAX_ROLL_DEPTH: u32 = 100;
 
 #[derive(Clone, Debug, Copy, Default, PartialEq, Eq)]
 pub struct StackVariable {
@@ -23,6 +40,7 @@ impl StackVariable {
     pub fn new(id: u32, size: u32) -> Self {
         StackVariable { id, size }
     }
+    // the id 0 is reserved for null, the tracker starts counting from 1
     pub fn null() -> Self {
         StackVariable { id: 0, size: 0 }
     }
@@ -49,13 +67,20 @@ enum RedoOps {
     RemoveVar(StackVariable),
     DecreaseSize(StackVariable),
     IncreaseSize(usize, u32),
+    Use(StackVariable),
+    Renumber(BTreeMap<u32, u32>),
+    SetHeld(StackVariable, bool),
 }
 
 #[derive(Clone, Debug)]
 pub struct StackData {
     pub(crate) stack: Vec<StackVariable>,
     pub(crate) altstack: Vec<StackVariable>,
-    pub(crate) names: HashMap<u32, String>,
+    pub(crate) names: BTreeMap<u32, String>,
+    // step where each variable was consumed, to explain the errors when it is used again
+    consumed: BTreeMap<u32, usize>,
+    // variables that the caller marked as held with StackTracker::hold, the debugger highlights them
+    held: BTreeSet<u32>,
     redo_log: Vec<RedoOps>,
     with_redo_log: bool,
 }
@@ -65,13 +90,28 @@ impl StackData {
         StackData {
             stack: Vec::new(),
             altstack: Vec::new(),
-            names: HashMap::new(),
+            names: BTreeMap::new(),
+            consumed: BTreeMap::new(),
+            held: BTreeSet::new(),
             redo_log: Vec::new(),
             with_redo_log
         }
     }
 
+    pub fn stack(&self) -> &[StackVariable] {
+        &self.stack
+    }
+
+    pub fn altstack(&self) -> &[StackVariable] {
+        &self.altstack
+    }
+
+    pub fn get_name(&self, var: StackVariable) -> Option<&str> {
+        self.names.get(&var.id).map(|n| n.as_str())
+    }
+
     pub fn push_stack(&mut self, var: StackVariable) {
+        debug_assert!(!var.is_null(), "The id 0 is reserved for StackVariable::null()");
         self.stack.push(var);
         if self.with_redo_log {
             self.redo_log.push(RedoOps::PushStack(var));
@@ -79,6 +119,7 @@ impl StackData {
     }
 
     pub fn push_altstack(&mut self, var: StackVariable) {
+        debug_assert!(!var.is_null(), "The id 0 is reserved for StackVariable::null()");
         self.altstack.push(var);
         if self.with_redo_log {
             self.redo_log.push(RedoOps::PushAltstack(var));
@@ -121,6 +162,7 @@ impl StackData {
     }
 
     pub fn insert_var(&mut self, pos: usize, var: StackVariable) {
+        debug_assert!(!var.is_null(), "The id 0 is reserved for StackVariable::null()");
         self.stack.insert(pos, var);
         if self.with_redo_log {
             self.redo_log.push(RedoOps::InsertVar(pos, var));
@@ -147,6 +189,66 @@ impl StackData {
         }
     }
 
+    // changes the ids of the variables (old id => new id), the ids not in the map are kept
+    pub fn renumber(&mut self, map: &BTreeMap<u32, u32>) {
+        let new_id = |id: u32| map.get(&id).copied().unwrap_or(id);
+        for var in self.stack.iter_mut().chain(self.altstack.iter_mut()) {
+            var.id = new_id(var.id);
+        }
+        self.names = core::mem::take(&mut self.names).into_iter().map(|(id, name)| (new_id(id), name)).collect();
+        self.consumed = core::mem::take(&mut self.consumed).into_iter().map(|(id, c)| (new_id(id), c)).collect();
+        self.held = core::mem::take(&mut self.held).into_iter().map(new_id).collect();
+        if self.with_redo_log {
+            self.redo_log.push(RedoOps::Renumber(map.clone()));
+        }
+    }
+
+    // records that the variable is gone (dropped or consumed by an operation) at the given step
+    pub fn mark_consumed(&mut self, var: StackVariable, step: usize) {
+        self.consumed.insert(var.id, step);
+    }
+
+    pub fn set_held(&mut self, var: StackVariable, held: bool) {
+        if held {
+            self.held.insert(var.id);
+        } else {
+            self.held.remove(&var.id);
+        }
+        if self.with_redo_log {
+            self.redo_log.push(RedoOps::SetHeld(var, held));
+        }
+    }
+
+    pub fn is_held(&self, var: StackVariable) -> bool {
+        self.held.contains(&var.id)
+    }
+
+    // step where the variable was consumed and the last name it had. The name is looked up in the redo log
+    // (going back through the renumbers), so it's empty for a tracker without redo log
+    pub fn consumed_at(&self, var: StackVariable) -> Option<(usize, String)> {
+        let step = *self.consumed.get(&var.id)?;
+        let mut id = var.id;
+        for op in self.redo_log.iter().rev() {
+            match op {
+                RedoOps::SetName(v, name) if v.id == id => return Some((step, name.clone())),
+                RedoOps::Renumber(map) => {
+                    if let Some((old, _)) = map.iter().find(|(_, new)| **new == id) {
+                        id = *old;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some((step, String::new()))
+    }
+
+    // records that the variable was read or consumed by an operation (moving or dropping it is not a use)
+    pub fn mark_used(&mut self, var: StackVariable) {
+        if self.with_redo_log {
+            self.redo_log.push(RedoOps::Use(var));
+        }
+    }
+
     pub fn new_from_redo_height(&self, height: usize) -> Self {
         let mut new_stack = StackData::new(false);
         for i in 0..height {
@@ -161,6 +263,9 @@ impl StackData {
                 RedoOps::InsertVar(pos, var) => new_stack.insert_var(*pos, *var),
                 RedoOps::DecreaseSize(var) => new_stack.decrease_size(*var),
                 RedoOps::IncreaseSize(idx, next_size) => new_stack.increase_size(*idx, *next_size),
+                RedoOps::Use(var) => new_stack.mark_used(*var),
+                RedoOps::Renumber(map) => new_stack.renumber(map),
+                RedoOps::SetHeld(var, held) => new_stack.set_held(*var, *held),
             }
         }
         new_stack
@@ -169,15 +274,202 @@ impl StackData {
 
 }
 
+// describes the elements provided by the witness, from the bottom to the top of the stack
+#[derive(Clone, Debug, Default)]
+pub struct WitnessLayout {
+    vars: Vec<(String, Vec<Vec<u8>>)>,
+}
+
+impl WitnessLayout {
+    pub fn new() -> Self {
+        WitnessLayout { vars: Vec::new() }
+    }
+
+    // adds a variable composed of one element per value
+    pub fn push(&mut self, name: &str, values: Vec<Vec<u8>>) -> &mut Self {
+        assert!(!values.is_empty(), "The witness variable {} needs at least one element", name);
+        self.vars.push((name.to_string(), values));
+        self
+    }
+
+    pub fn push_bytes(&mut self, name: &str, bytes: Vec<u8>) -> &mut Self {
+        self.push(name, vec![bytes])
+    }
+
+    pub fn push_number(&mut self, name: &str, value: i64) -> &mut Self {
+        self.push(name, vec![encode_number(value)])
+    }
+
+    pub fn witness(&self) -> Vec<Vec<u8>> {
+        self.vars.iter().flat_map(|(_, values)| values.iter().cloned()).collect()
+    }
+}
+
+// callback called with the label of each emitted operation and the model after it
+pub type Observer = Box<dyn FnMut(&str, &StackData)>;
+
+// the observer is not cloned, a cloned tracker starts without observer
+#[derive(Default)]
+struct ObserverSlot(Option<Observer>);
+
+impl Clone for ObserverSlot {
+    fn clone(&self) -> Self {
+        ObserverSlot(None)
+    }
+}
+
+impl fmt::Debug for ObserverSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", if self.0.is_some() { "Some(observer)" } else { "None" })
+    }
+}
+
+// suspicious patterns found by StackTracker::lint
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LintWarning {
+    // the variable was dropped without being read or consumed by any operation
+    UnusedVariable { var: StackVariable, name: String },
+    // the variable is still on the altstack at the end of the script
+    LeftoverAltstack { var: StackVariable, name: String },
+    // the step rolls an element deeper than LINT_MAX_ROLL_DEPTH
+    DeepRoll { step: usize, label: String, depth: u32 },
+    // executing the script the sizes of the (stack, altstack) after the step are not the ones of the model.
+    // usually a custom script with the wrong consumes or outputs. Only the first one is reported
+    ModelMismatch { step: usize, label: String, expected: (u32, u32), actual: (u32, u32) },
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LintWarning::UnusedVariable { var, name } => write!(f, "The variable {} ({:?}) is never used", name, var),
+            LintWarning::LeftoverAltstack { var, name } => write!(f, "The variable {} ({:?}) is left on the altstack", name, var),
+            LintWarning::DeepRoll { step, label, depth } => write!(f, "The step {} ({}) rolls from depth {}", step, label, depth),
+            LintWarning::ModelMismatch { step, label, expected, actual } =>
+                write!(f, "After the step {} ({}) the model expects (stack, altstack) sizes {:?} but the script has {:?}", step, label, expected, actual),
+        }
+    }
+}
+
+// failed execution returned by StackTracker::try_run
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptFailure {
+    // step that failed, the last one if the script ends without a single true value on the stack
+    pub step: usize,
+    pub label: String,
+    pub last_opcode: String,
+    pub error_msg: String,
+}
+
+impl fmt::Display for ScriptFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The script failed at step {} ({}) on {}: {}", self.step, self.label, self.last_opcode, self.error_msg)
+    }
+}
+
+// size of the script and the moves it uses, returned by StackTracker::stats
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub script_size: usize,
+    pub max_stack_size: u32,
+    // OP_ROLL and OP_PICK with a known depth (preceded by a number)
+    pub total_rolls: usize,
+    pub total_picks: usize,
+    pub max_roll_depth: u32,
+}
+
+// reference to the variable that plays a role (see StackTracker::bind), valid while the variable changes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handle {
+    role: String,
+}
+
+impl Handle {
+    pub fn role(&self) -> &str {
+        &self.role
+    }
+
+    pub fn var(&self, stack: &StackTracker) -> StackVariable {
+        stack.resolve(&self.role)
+    }
+}
+
+// arguments of a function built on the tracker (see StackTracker::push_frame).
+// the variables keep their id while they are moved around, so arg(i) is valid while the argument is not consumed
+#[derive(Clone, Debug)]
+pub struct Frame {
+    args: Vec<StackVariable>,
+}
+
+impl Frame {
+    pub fn arg(&self, i: usize) -> StackVariable {
+        assert!(i < self.args.len(), "The frame has {} arguments, {} is not valid", self.args.len(), i);
+        self.args[i]
+    }
+
+    pub fn len(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty()
+    }
+
+    // removes the arguments that were not consumed, leaving the rest of the stack in the same order
+    pub fn drop_frame(self, stack: &mut StackTracker) {
+        let mut alive = self.args.iter()
+            .filter_map(|arg| stack.data.stack.iter().position(|v| v.id == arg.id).map(|pos| (pos, *arg)))
+            .collect::<Vec<(usize, StackVariable)>>();
+        alive.sort_by_key(|(pos, _)| core::cmp::Reverse(*pos));
+        for (_, arg) in alive {
+            if stack.get_var_from_stack(0).id == arg.id {
+                stack.drop(arg);
+            } else {
+                stack.nip_var(arg);
+            }
+        }
+    }
+}
+
+// builds the body of a case of StackTracker::switch
+pub type CaseBuilder = Box<dyn FnOnce(&mut StackTracker)>;
+
+// state of the tracker saved by checkpoint, only valid to rollback the tracker that created it
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    stack: Vec<StackVariable>,
+    altstack: Vec<StackVariable>,
+    names: BTreeMap<u32, String>,
+    held: BTreeSet<u32>,
+    bindings: BTreeMap<String, u32>,
+    redo_log_len: usize,
+    script_len: usize,
+    history_len: usize,
+    labels_len: usize,
+    breakpoint_len: usize,
+    comments_len: usize,
+    constants_len: usize,
+    counter: u32,
+    max_stack_size: u32,
+}
+
 #[derive(Clone, Debug)]
 pub struct StackTracker {
     pub(crate) data: StackData,
     pub(crate) script: Vec<Script>,
     pub(crate) history: Vec<u32>,
+    pub(crate) labels: Vec<String>,
     counter: u32,
     max_stack_size: u32,
-    with_history: bool,
+    pub(crate) with_history: bool,
     pub(crate) breakpoint: Vec<(u32, String)>,
+    pub(crate) comments: Vec<(u32, String)>,
+    pub(crate) witness: Vec<Vec<u8>>,
+    base: Base,
+    constants: Vec<(Vec<u8>, StackVariable)>,
+    max_table_if_size: u32,
+    // role => id of the variable bound to it (see bind)
+    bindings: BTreeMap<String, u32>,
+    observer: ObserverSlot,
 }
 
 impl Default for StackTracker {
@@ -186,6 +478,24 @@ impl Default for StackTracker {
     }
 }
 
+// amount of variables shown by the Display implementation
+const DISPLAY_TOP_VARS: usize = 3;
+
+// operand names longer than this are shown as "\u{2026}" in the name of the result of an operation
+const MAX_OPERAND_NAME_LEN: usize = 40;
+
+impl fmt::Display for StackTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let depth = self.current_depth();
+        let alt_depth: u32 = self.data.altstack.iter().map(|v| v.size).sum();
+        let top = self.data.stack.iter().rev().take(DISPLAY_TOP_VARS)
+            .map(|v| self.data.names.get(&v.id).map(|n| n.as_str()).unwrap_or("unknown"))
+            .collect::<Vec<&str>>();
+        write!(f, "StackTracker {{ steps: {}, stack: {} ({} vars), altstack: {} ({} vars), max stack size: {}, top: [{}] }}",
+            self.script.len(), depth, self.data.stack.len(), alt_depth, self.data.altstack.len(), self.max_stack_size, top.join(", "))
+    }
+}
+
 impl StackTracker {
 
     pub fn new() -> Self {
@@ -193,38 +503,158 @@ impl StackTracker {
             data: StackData::new(true),
             script: Vec::new(),
             history: Vec::new(),
+            labels: Vec::new(),
             counter: 0,
             max_stack_size: 0,
             with_history: true,
             breakpoint: Vec::new(),
+            comments: Vec::new(),
+            witness: Vec::new(),
+            base: Base::default(),
+            constants: Vec::new(),
+            max_table_if_size: MAX_TABLE_IF_SIZE,
+            bindings: BTreeMap::new(),
+            observer: ObserverSlot::default(),
+        }
+    }
+
+    // creates a tracker without redo log and history to reduce the memory used on big builds.
+    // get_script() and run() work as usual, but it is not possible to debug intermediate steps
+    // (execute_step and interactive panic for any step but the last one)
+    pub fn new_no_debug() -> Self {
+        let mut stack = Self::new();
+        stack.data = StackData::new(false);
+        stack.with_history = false;
+        stack
+    }
+
+    // creates a tracker with the variables of the witness already defined.
+    // the witness is used as initial stack when running the script
+    pub fn from_witness(layout: &WitnessLayout) -> Self {
+        let mut stack = Self::new();
+        for (name, values) in layout.vars.iter() {
+            stack.define(values.len() as u32, name);
         }
+        stack.witness = layout.witness();
+        stack
+    }
+
+    pub fn new_with_base(base: Base) -> Self {
+        let mut stack = Self::new();
+        stack.base = base;
+        stack
+    }
+
+    // pay to pubkey template: <pubkey> OP_CHECKSIGVERIFY OP_TRUE.
+    // the signature is expected in the witness and is defined as the initial stack of the model
+    pub fn p2pk(pubkey: &[u8]) -> Self {
+        let mut stack = Self::new();
+        stack.define(1, "signature");
+        stack.var(1, script!{ { pubkey.to_vec() } }, "pubkey");
+        stack.op_checksigverify();
+        stack.op_true();
+        stack
+    }
+
+    // pay to pubkey hash template: OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG.
+    // the signature and the pubkey (on top) are expected in the witness and are defined as the initial stack of the model
+    pub fn p2pkh(pubkey_hash: &[u8]) -> Self {
+        assert_eq!(pubkey_hash.len(), 20, "hash160 digest needs to be 20 bytes long");
+        let mut stack = Self::new();
+        stack.define(1, "signature");
+        stack.define(1, "pubkey");
+        stack.op_dup();
+        stack.op_hash160();
+        stack.var(1, script!{ { pubkey_hash.to_vec() } }, "pubkey_hash");
+        stack.op_equalverify();
+        stack.op_checksig();
+        stack
+    }
+
+    pub fn get_base(&self) -> Base {
+        self.base
+    }
+
+    // the script of select_from_table_if grows with the size of the table, so bigger tables need to be allowed explicitly
+    pub fn set_max_table_if_size(&mut self, size: u32) {
+        self.max_table_if_size = size;
+    }
+
+    pub fn get_max_table_if_size(&self) -> u32 {
+        self.max_table_if_size
+    }
+
+    // amount of elements used by a u32 in the current base
+    pub fn u32_size(&self) -> u32 {
+        self.base.elements(32)
     }
 
     fn remove_var(&mut self, var: StackVariable) {
+        self.consume(var);
         self.data.remove_var(var);
         self.data.remove_name(var);
     }
 
+    // the variable is consumed by the step that is going to be pushed next
+    fn consume(&mut self, var: StackVariable) {
+        self.data.mark_consumed(var, self.script.len());
+        if self.data.is_held(var) {
+            self.data.set_held(var, false);
+        }
+    }
+
+    // explains why a variable is not on the stack if it was already consumed
+    fn assert_not_consumed(&self, var: StackVariable) {
+        if let Some((step, name)) = self.data.consumed_at(var) {
+            if !self.data.stack.iter().any(|v| v.id == var.id) {
+                panic!("The variable {:?} ({}) was already consumed at step {}", var, name, step);
+            }
+        }
+    }
+
+    fn assert_not_on_altstack(&self, var: StackVariable) {
+        if self.data.altstack.iter().any(|v| v.id == var.id) {
+            panic!("The variable {:?} ({}) is on the altstack, use from_altstack first", var, self.get_var_name(var));
+        }
+    }
+
     fn push(&mut self, var: StackVariable) {
         self.data.push_stack(var);
         let totalsize = self.data.stack.iter().fold(0, |acc, f| acc + f.size);
         self.max_stack_size = self.max_stack_size.max(totalsize);
     }
 
-    fn push_script(&mut self, script: Script) {
+    fn push_script(&mut self, script: Script, label: &str) {
         self.script.push(script);
         if self.with_history {
             self.history.push(self.data.redo_log.len() as u32);
+            self.labels.push(label.to_string());
         }
+        if let Some(observer) = self.observer.0.as_mut() {
+            observer(label, &self.data);
+        }
+    }
+
+    pub fn set_observer(&mut self, observer: Observer) {
+        self.observer = ObserverSlot(Some(observer));
+    }
+
+    pub fn clear_observer(&mut self) {
+        self.observer = ObserverSlot(None);
+    }
+
+    // name of the operation that produced the step
+    pub fn step_label(&self, step: usize) -> Option<&str> {
+        self.labels.get(step).map(|l| l.as_str())
     }
 
     pub fn set_breakpoint(&mut self, name: &str) {
-        self.push_script(script!{});
+        self.push_script(script!{}, &format!("breakpoint({})", name));
         self.breakpoint.push((self.script.len()as u32 - 1, name.to_string()));
     }
 
-    pub fn get_next_breakpoint(&self, from:u32) -> Option<(u32, String)> {
-        for (pos, name) in self.breakpoint.iter() {
+    fn get_next_mark(&self, marks: &[(u32, String)], from: u32) -> Option<(u32, String)> {
+        for (pos, name) in marks.iter() {
             if *pos > from {
                 let pos = *pos;
                 let pos = pos.min(self.script.len() as u32 - 1);
@@ -234,9 +664,9 @@ impl StackTracker {
         None
     }
 
-    pub fn get_prev_breakpoint(&self, from:u32) -> Option<(u32, String)> {
+    fn get_prev_mark(&self, marks: &[(u32, String)], from: u32) -> Option<(u32, String)> {
         let mut ret = None;
-        for (pos, name) in self.breakpoint.iter() {
+        for (pos, name) in marks.iter() {
             if *pos < from {
                 ret = Some((*pos, name.clone()));
             }
@@ -247,11 +677,217 @@ impl StackTracker {
         ret
     }
 
+    pub fn get_next_breakpoint(&self, from:u32) -> Option<(u32, String)> {
+        self.get_next_mark(&self.breakpoint, from)
+    }
+
+    pub fn get_prev_breakpoint(&self, from:u32) -> Option<(u32, String)> {
+        self.get_prev_mark(&self.breakpoint, from)
+    }
+
+    // comments mark the start of a section of the script, they work as soft breakpoints in the interactive debugger
+    pub fn comment(&mut self, text: &str) {
+        self.push_script(script!{}, &format!("comment({})", text));
+        self.comments.push((self.script.len() as u32 - 1, text.to_string()));
+    }
+
+    pub fn get_next_comment(&self, from:u32) -> Option<(u32, String)> {
+        self.get_next_mark(&self.comments, from)
+    }
+
+    pub fn get_prev_comment(&self, from:u32) -> Option<(u32, String)> {
+        self.get_prev_mark(&self.comments, from)
+    }
+
+    // name of the section (last comment) that contains the step
+    pub fn get_section(&self, step: u32) -> Option<&str> {
+        self.comments.iter().rev().find(|(pos, _)| *pos <= step).map(|(_, name)| name.as_str())
+    }
+
+
+    // how many times each depth is rolled in the script (OP_SWAP and OP_ROT count as rolls of depth 1 and 2).
+    // OP_ROLL with a depth computed at runtime are not counted
+    pub fn roll_depth_histogram(&self) -> BTreeMap<u32, usize> {
+        let mut ret = BTreeMap::new();
+        for depth in roll_depths(&self.get_script()) {
+            *ret.entry(depth).or_insert(0) += 1;
+        }
+        ret
+    }
+
+    pub fn stats(&self) -> Stats {
+        let script = self.get_script();
+        let rolls = pushed_depths(&script, OP_ROLL);
+        Stats {
+            script_size: script.len(),
+            max_stack_size: self.max_stack_size,
+            total_rolls: rolls.len(),
+            total_picks: pushed_depths(&script, OP_PICK).len(),
+            max_roll_depth: rolls.into_iter().max().unwrap_or(0),
+        }
+    }
+
+    // returns the opcodes of the script that are not valid in tapscript (see invalid_tapscript_opcodes)
+    pub fn assert_tapscript_valid(&self) -> Result<(), Vec<Opcode>> {
+        let invalid = invalid_tapscript_opcodes(&self.get_script());
+        if invalid.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid)
+        }
+    }
+
+    // looks for suspicious patterns on the built script (see LintWarning).
+    // the script is executed to compare the model with the real stack so it needs the redo log and history (StackTracker::new)
+    #[cfg(feature = "std")]
+    pub fn lint(&self) -> Vec<LintWarning> {
+        assert!(self.with_history && self.data.with_redo_log, "lint requires the history, create the tracker with StackTracker::new");
+        let mut ret = Vec::new();
+
+        // the variables as they were created and their last name, with the ids translated by normalize_ids
+        let mut defined: Vec<StackVariable> = Vec::new();
+        let mut used = Vec::new();
+        let mut names = BTreeMap::new();
+        for op in self.data.redo_log.iter() {
+            match op {
+                RedoOps::PushStack(var) | RedoOps::PushAltstack(var) | RedoOps::InsertVar(_, var) if !defined.iter().any(|v| v.id == var.id) => {
+                    defined.push(*var);
+                }
+                RedoOps::Use(var) => used.push(var.id),
+                RedoOps::SetName(var, name) => { names.insert(var.id, name.clone()); }
+                RedoOps::Renumber(map) => {
+                    let new_id = |id: u32| map.get(&id).copied().unwrap_or(id);
+                    defined.iter_mut().for_each(|v| v.id = new_id(v.id));
+                    used.iter_mut().for_each(|id| *id = new_id(*id));
+                    names = names.into_iter().map(|(id, name)| (new_id(id), name)).collect();
+                }
+                _ => {}
+            }
+        }
+        for var in defined {
+            let alive = self.data.stack.iter().chain(self.data.altstack.iter()).any(|v| v.id == var.id);
+            if !alive && !used.contains(&var.id) {
+                let name = names.get(&var.id).cloned().unwrap_or_default();
+                ret.push(LintWarning::UnusedVariable { var, name });
+            }
+        }
+
+        for var in self.data.altstack.iter() {
+            let name = self.data.get_name(*var).unwrap_or_default().to_string();
+            ret.push(LintWarning::LeftoverAltstack { var: *var, name });
+        }
+
+        for (step, script) in self.script.iter().enumerate() {
+            if let Some(depth) = roll_depths(script).into_iter().filter(|d| *d > LINT_MAX_ROLL_DEPTH).max() {
+                ret.push(LintWarning::DeepRoll { step, label: self.labels[step].clone(), depth });
+            }
+        }
+
+        for (step, actual) in execute_step_sizes(self).into_iter().enumerate() {
+            let data = self.data.new_from_redo_height(self.history[step] as usize);
+            let size = |vars: &[StackVariable]| vars.iter().map(|v| v.size).sum::<u32>();
+            let expected = (size(&data.stack), size(&data.altstack));
+            if expected != actual {
+                ret.push(LintWarning::ModelMismatch { step, label: self.labels[step].clone(), expected, actual });
+                break;
+            }
+        }
+
+        ret
+    }
+
+    // renumbers the variables so the ones on the stack (from the bottom) and then the ones on the altstack take the ids
+    // from 1, to compare the models of trackers that created a different amount of variables before.
+    // the rest of the ids up to the counter are given to the variables that are gone, so the new ones don't collide.
+    // the variables held before calling it are no longer valid, they need to be read again from the stack
+    pub fn normalize_ids(&mut self) {
+        let mut map = BTreeMap::new();
+        for var in self.data.stack.iter().chain(self.data.altstack.iter()) {
+            map.insert(var.id, map.len() as u32 + 1);
+        }
+        for id in 1..=self.counter {
+            if !map.contains_key(&id) {
+                map.insert(id, map.len() as u32 + 1);
+            }
+        }
+        self.data.renumber(&map);
+        for id in self.bindings.values_mut() {
+            *id = map[id];
+        }
+        for (_, var) in self.constants.iter_mut() {
+            var.id = map[&var.id];
+        }
+        // the renumbering belongs to the last step
+        if let Some(last) = self.history.last_mut() {
+            *last = self.data.redo_log.len() as u32;
+        }
+    }
 
     pub fn get_max_stack_size(&self) -> u32 {
         self.max_stack_size
     }
 
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            stack: self.data.stack.clone(),
+            altstack: self.data.altstack.clone(),
+            names: self.data.names.clone(),
+            held: self.data.held.clone(),
+            bindings: self.bindings.clone(),
+            redo_log_len: self.data.redo_log.len(),
+            script_len: self.script.len(),
+            history_len: self.history.len(),
+            labels_len: self.labels.len(),
+            breakpoint_len: self.breakpoint.len(),
+            comments_len: self.comments.len(),
+            constants_len: self.constants.len(),
+            counter: self.counter,
+            max_stack_size: self.max_stack_size,
+        }
+    }
+
+    // discards everything done after the checkpoint was taken
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        assert!(checkpoint.script_len <= self.script.len() && checkpoint.redo_log_len <= self.data.redo_log.len(),
+            "The checkpoint is newer than the current state of the tracker");
+        self.data.stack = checkpoint.stack;
+        self.data.altstack = checkpoint.altstack;
+        self.data.names = checkpoint.names;
+        // the variables consumed after the checkpoint are the only ones with a later step
+        self.data.consumed.retain(|_, step| *step < checkpoint.script_len);
+        self.data.held = checkpoint.held;
+        self.bindings = checkpoint.bindings;
+        self.data.redo_log.truncate(checkpoint.redo_log_len);
+        self.script.truncate(checkpoint.script_len);
+        self.history.truncate(checkpoint.history_len);
+        self.labels.truncate(checkpoint.labels_len);
+        self.breakpoint.truncate(checkpoint.breakpoint_len);
+        self.comments.truncate(checkpoint.comments_len);
+        self.constants.truncate(checkpoint.constants_len);
+        self.counter = checkpoint.counter;
+        self.max_stack_size = checkpoint.max_stack_size;
+    }
+
+    // builds a gadget keeping it only if `f` returns true, otherwise the tracker is left as before
+    pub fn try_build(&mut self, f: impl FnOnce(&mut StackTracker) -> bool) -> bool {
+        let checkpoint = self.checkpoint();
+        let keep = f(self);
+        if !keep {
+            self.rollback(checkpoint);
+        }
+        keep
+    }
+
+    // amount of elements on the modeled stack (what op_depth would push at this point)
+    pub fn current_depth(&self) -> u32 {
+        self.data.stack.iter().map(|v| v.size).sum()
+    }
+
+    pub fn current_var_count(&self) -> usize {
+        self.data.stack.len()
+    }
+
+    // ids start at 1 as 0 is StackVariable::null()
     pub fn next_counter(&mut self) -> u32 {
         self.counter += 1;
         self.counter
@@ -283,6 +919,127 @@ impl StackTracker {
             }, consumes, output_vars, to_altstack)
     }
 
+    // closes the if inferring what the branches consume and produce by comparing them with the model before the if.
+    // both branches need to consume the same variables and produce variables of the same sizes
+    // (the names are taken from the true branch), and they can not modify the altstack
+    pub fn end_if_auto(&mut self, if_true: StackTracker, if_false: StackTracker) -> Vec<StackVariable> {
+        let base_len = self.data.stack.len();
+        let (consumes, output) = self.branch_shape_from(base_len, &if_true);
+        let (false_consumes, false_output) = self.branch_shape_from(base_len, &if_false);
+        let sizes = |output: &Vec<(u32, String)>| output.iter().map(|(size, _)| *size).collect::<Vec<u32>>();
+        assert!(consumes == false_consumes && sizes(&output) == sizes(&false_output),
+            "The branches of the if need to consume and produce the same variables: the true branch consumes {} and produces {:?}, the false branch consumes {} and produces {:?}",
+            consumes, sizes(&output), false_consumes, sizes(&false_output));
+        self.end_if(if_true, if_false, consumes, output, 0)
+    }
+
+    // dispatches on the selector (that needs to be on top of the stack) comparing it with the value of each case,
+    // running the default branch if none matches. The selector is consumed.
+    // As with open_if, all the branches need to consume the same variables and produce variables of the same sizes
+    // (the names are taken from the first branch), and they can not modify the altstack
+    pub fn switch(&mut self, selector: StackVariable, cases: Vec<(i64, CaseBuilder)>, default: CaseBuilder) -> Vec<StackVariable> {
+        assert_eq!(self.get_var_from_stack(0).id, selector.id, "The selector {:?} needs to be on top of the stack", selector);
+        assert_eq!(selector.size, 1, "The selector {:?} needs to be of size 1", selector);
+
+        let mut shape = None;
+        let mut build_branch = |stack: &StackTracker, builder: CaseBuilder| {
+            let mut branch = stack.clone();
+            branch.op_drop();
+            builder(&mut branch);
+            let branch_shape = stack.branch_shape(&branch);
+            match &shape {
+                None => shape = Some(branch_shape),
+                Some((consumes, output)) => {
+                    let sizes = |output: &Vec<(u32, String)>| output.iter().map(|(size, _)| *size).collect::<Vec<u32>>();
+                    assert!(*consumes == branch_shape.0 && sizes(output) == sizes(&branch_shape.1), "All the branches of the switch need to consume and produce the same variables");
+                }
+            }
+            script! {
+                for s in branch.script.iter().skip(stack.script.len()) {
+                    { s.clone() }
+                }
+            }
+        };
+
+        let bodies = cases.into_iter().map(|(k, builder)| (k, build_branch(self, builder))).collect::<Vec<(i64, Script)>>();
+        let mut script = build_branch(self, default);
+        for (k, body) in bodies.into_iter().rev() {
+            script = script! {
+                OP_DUP { k } OP_NUMEQUAL
+                OP_IF
+                    { body }
+                OP_ELSE
+                    { script }
+                OP_ENDIF
+            };
+        }
+
+        let (consumes, output) = shape.unwrap();
+        self.custom_ex_labeled(script, consumes + 1, output, 0, "switch")
+    }
+
+    // emulates "while cond { body }" running at most max_iters iterations. As there are no loops the body is unrolled
+    // max_iters times, each copy guarded by the condition with OP_IF, so once the condition is false the rest of the
+    // iterations do nothing. The cost is max_iters times the script of cond and body (plus OP_IF OP_ENDIF) no matter
+    // when the loop stops.
+    // `cond` needs to push a boolean on top without consuming anything and it needs to keep being false once it is.
+    // `body` needs to produce variables of the same sizes that it consumes and can not modify the altstack.
+    // returns the variables produced by the last iteration
+    pub fn bounded_while(&mut self, max_iters: u32, body: impl Fn(&mut StackTracker), cond: impl Fn(&mut StackTracker) -> StackVariable) -> Vec<StackVariable> {
+        let mut ret = Vec::new();
+        for _ in 0..max_iters {
+            let before = self.data.stack.clone();
+            let flag = cond(self);
+            assert!(self.data.stack.len() == before.len() + 1 && self.data.stack[..before.len()] == before[..] && flag.size == 1,
+                "The condition of bounded_while needs to push one element without consuming any variable");
+
+            // OP_IF consumes the flag
+            let mut branch = self.clone();
+            branch.data.pop_stack();
+            body(&mut branch);
+            let (consumes, output) = self.branch_shape(&branch);
+            let consumed = before[before.len() - consumes as usize..].iter().map(|v| v.size).collect::<Vec<u32>>();
+            assert_eq!(consumed, output.iter().map(|(size, _)| *size).collect::<Vec<u32>>(),
+                "The body of bounded_while needs to produce variables of the same sizes that it consumes");
+
+            let script = script! {
+                OP_IF
+                    for s in branch.script.iter().skip(self.script.len()) {
+                        { s.clone() }
+                    }
+                OP_ENDIF
+            };
+            ret = self.custom_ex_labeled(script, consumes + 1, output, 0, "bounded_while");
+        }
+        ret
+    }
+
+    // variables of this tracker consumed by the branch (besides the selector) and the variables it produces
+    fn branch_shape(&self, branch: &StackTracker) -> (u32, Vec<(u32, String)>) {
+        self.branch_shape_from(self.data.stack.len() - 1, branch)
+    }
+
+    // same as branch_shape but comparing against the first `base_len` variables of the stack
+    fn branch_shape_from(&self, base_len: usize, branch: &StackTracker) -> (u32, Vec<(u32, String)>) {
+        assert_eq!(self.data.altstack, branch.data.altstack, "The branches can not modify the altstack");
+        let base = &self.data.stack[..base_len];
+        let kept = base.iter().zip(branch.data.stack.iter()).take_while(|(a, b)| a == b).count();
+        let output = branch.data.stack[kept..].iter().map(|v| (v.size, branch.get_var_name(*v))).collect();
+        ((base.len() - kept) as u32, output)
+    }
+
+    // brings the arguments to the top of the stack (the last one on top) unless they are already there
+    pub fn push_frame(&mut self, args: Vec<StackVariable>) -> Frame {
+        let len = self.data.stack.len();
+        let in_place = args.len() <= len && self.data.stack[len - args.len()..].iter().zip(args.iter()).all(|(v, a)| v.id == a.id);
+        if !in_place {
+            for arg in args.iter() {
+                self.move_var(*arg);
+            }
+        }
+        Frame { args }
+    }
+
     pub fn clear_definitions(&mut self) {
         let vars = self.data.stack.iter().cloned().collect::<Vec<StackVariable>>();
         for v in vars {
@@ -302,13 +1059,73 @@ impl StackTracker {
         let var = StackVariable::new( self.next_counter(), size );
         self.push(var);
         self.data.set_name(var, name);
-        self.push_script(script);
+        self.push_script(script, name);
         var
     }
 
     pub fn rename(&mut self, var: StackVariable, name: &str) {
         self.data.set_name(var, name);
-        self.push_script(script!{});
+        self.push_script(script!{}, &format!("rename({})", name));
+    }
+
+    // marks the variable as held by the caller (as opposed to scratch values) so the debugger highlights it.
+    // it stops being held when it's consumed or released
+    pub fn hold(&mut self, var: StackVariable) {
+        assert!(self.data.stack.iter().chain(self.data.altstack.iter()).any(|v| v.id == var.id), "The variable {:?} is not on the stacks", var);
+        self.set_held(var, true);
+    }
+
+    pub fn release(&mut self, var: StackVariable) {
+        self.set_held(var, false);
+    }
+
+    fn set_held(&mut self, var: StackVariable, held: bool) {
+        self.data.set_held(var, held);
+        // the change belongs to the current step
+        if let Some(last) = self.history.last_mut() {
+            *last = self.data.redo_log.len() as u32;
+        }
+    }
+
+    // binds the variable to a role, replacing the previous binding of the role.
+    // when the variable is joined into another one the role follows the resulting variable
+    pub fn bind(&mut self, var: StackVariable, role: &str) -> Handle {
+        self.bindings.insert(role.to_string(), var.id);
+        Handle { role: role.to_string() }
+    }
+
+    // the variable bound to the role, with its current size
+    pub fn resolve(&self, role: &str) -> StackVariable {
+        let id = *self.bindings.get(role).unwrap_or_else(|| panic!("There is no variable bound to the role {}", role));
+        self.data.stack.iter().chain(self.data.altstack.iter()).find(|v| v.id == id).cloned()
+            .unwrap_or_else(|| panic!("The variable {} bound to the role {} is not on the stack anymore", id, role))
+    }
+
+    // the roles of a variable absorbed by a join move to the variable that absorbs it
+    fn transfer_bindings(&mut self, from: StackVariable, to: StackVariable) {
+        for id in self.bindings.values_mut() {
+            if *id == from.id {
+                *id = to.id;
+            }
+        }
+    }
+
+    // renames the variables that share the same name adding a suffix (#2, #3...) in order of creation
+    pub fn dedupe_names(&mut self) {
+        let mut vars = self.data.stack.iter().chain(self.data.altstack.iter()).cloned().collect::<Vec<StackVariable>>();
+        vars.sort_by_key(|v| v.id);
+
+        let mut seen: BTreeMap<String, u32> = BTreeMap::new();
+        for var in vars {
+            let Some(name) = self.data.names.get(&var.id).cloned() else { continue };
+            let count = seen.entry(name.clone()).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                let new_name = format!("{}#{}", name, count);
+                self.data.set_name(var, &new_name);
+            }
+        }
+        self.push_script(script!{}, "dedupe_names");
     }
 
     pub fn get_size(&self, var: StackVariable) -> u32 {
@@ -318,14 +1135,43 @@ impl StackTracker {
     }
     
     pub fn drop(&mut self, var: StackVariable) {
+        self.assert_not_consumed(var);
         assert!(self.data.stack.last().unwrap().id == var.id);
         let size = self.get_size(var);
-        self.data.pop_stack();
-        self.data.remove_name(var);
-        self.push_script(drop_count(size));
+        self.pop_consumed(false);
+        self.push_script(drop_count(size), "drop");
     }
 
-    pub fn drop_list(&mut self, vars: Vec<StackVariable>) {
+    // removes the top variable of the stack (or the altstack) marking it as consumed
+    fn pop_consumed(&mut self, from_altstack: bool) -> StackVariable {
+        let var = if from_altstack { self.data.pop_altstack() } else { self.data.pop_stack() };
+        self.consume(var);
+        self.data.remove_name(var);
+        var
+    }
+
+    // removes a variable from any position of the stack keeping the order of the variables above it
+    pub fn nip_var(&mut self, var: StackVariable) {
+        self.move_var(var);
+        self.drop(var);
+    }
+
+    // keeps the top `count` variables (not elements, a variable can have many of them) dropping all the ones below.
+    // the kept variables are stashed on the altstack while the rest is dropped and then brought back in the same order
+    pub fn keep_top(&mut self, count: u32) {
+        let len = self.data.stack.len() as u32;
+        assert!(count <= len, "The stack has {} variables, it can't keep the top {}", len, count);
+        if count == len {
+            return;
+        }
+        self.to_altstack_count(count);
+        for _ in 0..len - count {
+            self.drop(self.get_var_from_stack(0));
+        }
+        self.from_altstack_count(count);
+    }
+
+    pub fn drop_list(&mut self, vars: Vec<StackVariable>) {
         for _ in 0..vars.len() {
             let v = self.get_var_from_stack(0);
             assert!(vars.contains(&v), "The variable {:?} is not in the list of variables to drop", v);
@@ -336,10 +1182,65 @@ impl StackTracker {
     pub fn to_altstack(&mut self) -> StackVariable {
         let var = self.data.pop_stack();
         self.data.push_altstack(var);
-        self.push_script( toaltstack(var.size) );
+        self.push_script( toaltstack(var.size), "to_altstack" );
         var
     }
 
+    // moves the top variable to the altstack, same as to_altstack
+    pub fn stash(&mut self) -> StackVariable {
+        self.to_altstack()
+    }
+
+    // brings `count` numbers from the altstack adding them as they arrive and leaves the sum on the stack.
+    // OP_ADD only takes operands of up to 4 bytes, so the numbers and every partial sum need to be in the
+    // range of a signed 32 bits number (the last sum can overflow but it can't be used as a number again)
+    pub fn sum_altstack(&mut self, count: u32) -> StackVariable {
+        assert!(count > 0, "sum_altstack needs at least one number");
+        let len = self.data.altstack.len() as u32;
+        assert!(count <= len, "The altstack has {} variables, it can't sum {}", len, count);
+        let mut sum = self.from_altstack();
+        for _ in 1..count {
+            self.from_altstack();
+            sum = self.op_add();
+        }
+        sum
+    }
+
+    // moves the variable from any position of the stack to the top of the altstack
+    pub fn move_var_to_altstack(&mut self, var: StackVariable) -> StackVariable {
+        self.move_var(var);
+        self.to_altstack()
+    }
+
+    // reverses the order of the variables of the altstack bringing them to the stack, reversing them there and sending them back.
+    // it's O(n): two opcodes per element to cycle them and a roll per element of every variable but one
+    pub fn reverse_altstack(&mut self) {
+        let count = self.data.altstack.len() as u32;
+        let vars = self.from_altstack_count(count);
+        for var in vars.into_iter().rev().skip(1) {
+            self.move_var(var);
+        }
+        self.to_altstack_count(count);
+    }
+
+    // keeps the top variable and saves a copy of it on the altstack
+    pub fn stash_copy(&mut self) -> StackVariable {
+        let var = self.get_var_from_stack(0);
+        let copy = StackVariable::new(self.next_counter(), var.size);
+        self.data.mark_used(var);
+        self.data.push_altstack(copy);
+        self.data.set_name(copy, &format!("copy({})", self.get_var_name(var)));
+        let script = if var.size == 1 {
+            script!{ OP_DUP OP_TOALTSTACK }
+        } else {
+            script!{ { copy_from(0, var.size) } { toaltstack(var.size) } }
+        };
+        self.push_script(script, "stash_copy");
+        copy
+    }
+
+    // moves the top `count` variables one by one, so they are reversed on the altstack (the top of the stack ends
+    // the deepest of them). from_altstack_count reverses them again, so a round trip already keeps the original order
     pub fn to_altstack_count(&mut self, count: u32) -> Vec<StackVariable> {
         let mut ret = Vec::new();
         for _ in 0..count {
@@ -350,10 +1251,20 @@ impl StackTracker {
    }
 
 
+    // moves the top `count` variables keeping their order on the altstack (the top of the stack ends at the top of the altstack).
+    // the variables are reversed before moving them, so bringing them back with from_altstack_count returns them reversed
+    pub fn to_altstack_same_order(&mut self, count: u32) -> Vec<StackVariable> {
+        let vars = (0..count).map(|i| self.get_var_from_stack(i)).collect::<Vec<StackVariable>>();
+        for var in vars.iter().skip(1) {
+            self.move_var(*var);
+        }
+        self.to_altstack_count(count)
+    }
+
     pub fn from_altstack(&mut self) -> StackVariable {
         let var = self.data.pop_altstack();
         self.push(var);
-        self.push_script( fromaltstack(var.size) );
+        self.push_script( fromaltstack(var.size), "from_altstack" );
         var
     }
 
@@ -374,14 +1285,54 @@ impl StackTracker {
         tmp[0]
     }
 
+    // explodes the variable on top of the stack sending its parts to the altstack. The most significant part (the first
+    // one returned) ends on top of the altstack, so bringing them back one by one leaves them in the original order
+    pub fn explode_to_altstack(&mut self, var: StackVariable) -> Vec<StackVariable> {
+        assert_eq!(self.data.stack.last().map(|v| v.id), Some(var.id), "The variable {:?} needs to be on top of the stack", var);
+        let parts = self.explode(var);
+        self.to_altstack_count(parts.len() as u32);
+        parts
+    }
+
+    // brings back the parts sent to the altstack by explode_to_altstack joining them in a variable
+    pub fn reassemble_from_altstack(&mut self, parts: &[StackVariable], name: &str) -> StackVariable {
+        let len = self.data.altstack.len();
+        assert!(parts.len() <= len && parts.iter().enumerate().all(|(i, p)| self.data.altstack[len - 1 - i].id == p.id),
+            "The parts {:?} are not on top of the altstack in order", parts);
+        if parts.len() == 1 {
+            let var = self.from_altstack();
+            self.rename(var, name);
+            return var;
+        }
+        self.from_altstack_joined(parts.len() as u32, name)
+    }
+
+    // the script of each step without cloning it (see concat_scripts to build the full script from them)
+    pub fn script_chunks(&self) -> impl Iterator<Item = &Script> {
+        self.script.iter()
+    }
+
     pub fn get_script(&self) -> Script {
-        script! {
-            for s in self.script.iter() {
-                { s.clone() }
+        concat_scripts(self.script_chunks())
+    }
+
+    // runs the optimizer inside each step, so the script is smaller but every step keeps its effect on the stack
+    // and the debugger stays aligned with the model. Patterns that cross steps are not optimized.
+    // steps with non minimal pushes are kept as they are, as the optimizer can not parse them
+    pub fn optimize_steps(&mut self) {
+        for step in self.script.iter_mut() {
+            if step.instructions_minimal().all(|i| i.is_ok()) {
+                *step = optimize(step.clone());
             }
         }
     }
 
+    // returns the script emitted between the steps `from` and `to` (both inclusive)
+    pub fn get_script_range(&self, from: usize, to: usize) -> Script {
+        assert!(from <= to && to < self.script.len(), "Invalid script range {}..={} (script len: {})", from, to, self.script.len());
+        concat_scripts(self.script[from..=to].iter())
+    }
+
     pub fn move_var(&mut self, var: StackVariable) -> StackVariable {
         let offset = self.get_offset(var);
         let size = self.get_size(var);
@@ -390,17 +1341,91 @@ impl StackTracker {
         }
         self.data.remove_var(var);
         self.push(var);
-        self.push_script( move_from(offset, size));
+        self.push_script( move_from(offset, size), "move_var");
         var
     }
     
     pub fn copy_var(&mut self, var: StackVariable) -> StackVariable {
+        if !self.data.stack.iter().any(|v| v.id == var.id) && self.data.altstack.iter().any(|v| v.id == var.id) {
+            return self.copy_altstack_var(var);
+        }
         let offset = self.get_offset(var);
         let size = self.get_size(var);
         let new_var = StackVariable::new(self.next_counter(), size);
+        self.data.mark_used(var);
         self.push(new_var);
-        self.rename(new_var, &format!("copy({})", self.data.names[&var.id]));
-        self.push_script( copy_from(offset, size));
+        self.data.set_name(new_var, &format!("copy({})", self.data.names[&var.id]));
+        self.push_script( copy_from(offset, size), "copy_var");
+        new_var
+    }
+
+    // makes n copies of the variable picking it only once, the rest of the copies duplicate the previous one
+    // with OP_DUP, OP_2DUP or OP_3DUP (or a shallow pick for bigger variables)
+    pub fn copy_var_times(&mut self, var: StackVariable, n: u32) -> Vec<StackVariable> {
+        if n == 0 {
+            return vec![];
+        }
+        let mut ret = vec![self.copy_var(var)];
+        let size = var.size();
+        let name = format!("copy({})", self.get_var_name(var));
+        for _ in 1..n {
+            let dup = match size {
+                1 => script!{ OP_DUP },
+                2 => script!{ OP_2DUP },
+                3 => script!{ OP_3DUP },
+                _ => copy_from(0, size),
+            };
+            let new_var = StackVariable::new(self.next_counter(), size);
+            self.data.mark_used(*ret.last().unwrap());
+            self.push(new_var);
+            self.data.set_name(new_var, &name);
+            self.push_script(dup, "copy_var");
+            ret.push(new_var);
+        }
+        ret
+    }
+
+    // copies the variable k times moving every copy to the altstack, named broadcast[i] with broadcast[0] on top.
+    // the variable stays where it is
+    pub fn broadcast_to_altstack(&mut self, var: StackVariable, k: u32) -> Vec<StackVariable> {
+        let copies = self.copy_var_times(var, k);
+        for (i, copy) in copies.iter().enumerate() {
+            self.named_result(*copy, Some(format!("broadcast[{}]", i)));
+        }
+        self.to_altstack_count(k);
+        copies
+    }
+
+    // brings the variable to the top of the stack, moving it if it is not going to be used again (cheaper) or copying it otherwise
+    pub fn use_var(&mut self, var: StackVariable, consume: bool) -> StackVariable {
+        if consume {
+            self.move_var(var)
+        } else {
+            self.copy_var(var)
+        }
+    }
+
+    // copies the variable at `depth` of the altstack (0 is the top) to the top of the stack leaving the altstack unchanged.
+    // it costs an OP_FROMALTSTACK and an OP_TOALTSTACK for every element up to the slot, the copy of the slot
+    // and a roll for every slot moved back over the copy
+    pub fn copy_altstack_slot(&mut self, depth: u32) -> StackVariable {
+        let len = self.data.altstack.len();
+        assert!((depth as usize) < len, "The altstack has {} variables, there is no slot at depth {}", len, depth);
+        let var = self.data.altstack[len - 1 - depth as usize];
+        self.copy_altstack_var(var)
+    }
+
+    // brings back the variables from the altstack, copies the variable and restores the altstack
+    fn copy_altstack_var(&mut self, var: StackVariable) -> StackVariable {
+        let pos = self.data.altstack.iter().position(|v| v.id == var.id).unwrap();
+        let count = (self.data.altstack.len() - pos) as u32;
+        let mut vars = self.from_altstack_count(count);
+        let new_var = self.copy_var(var);
+        vars.reverse();
+        for v in vars {
+            self.move_var(v);
+            self.to_altstack();
+        }
         new_var
     }
 
@@ -409,6 +1434,59 @@ impl StackTracker {
         assert_eq!(var1.size, var2.size, "The variables {:?} and {:?} are not the same size", var1, var2);
         assert_ne!(var1.id, var2.id, "The variables {:?} and {:?} are the same", var1, var2);
 
+        // both consumed and already on top: no need to model the moves one element at a time.
+        // (equalverify_adjacent emits a shorter script for this case)
+        if consume_1 && consume_2 && self.data.stack.len() >= 2 {
+            let (below, top) = (self.get_var_from_stack(1), self.get_var_from_stack(0));
+            if (below.id, top.id) == (var1.id, var2.id) || (below.id, top.id) == (var2.id, var1.id) {
+                assert_eq!(self.get_size(below), self.get_size(top), "The variables {:?} and {:?} are not the same size", below, top);
+                self.custom(verify_n(var1.size), 2, false, 0, "equals");
+                // same as the consuming path, both variables end with size 0
+                var1.size = 0;
+                var2.size = 0;
+                return;
+            }
+        }
+
+        // the comparison is symmetric, so the operands are swapped when it's estimated to be cheaper
+        let direct = self.equals_cost(*var1, *var2, consume_2);
+        let swapped = self.equals_cost(*var2, *var1, consume_1);
+        if swapped < direct {
+            self.equals_ordered(var2, consume_2, var1, consume_1);
+        } else {
+            self.equals_ordered(var1, consume_1, var2, consume_2);
+        }
+    }
+
+    // compares every pair with equals. Instead of following the given order, the pair with the shallowest
+    // deepest variable is compared first, so the pairs at the top of the stack don't need to be rolled over the rest
+    pub fn equals_many(&mut self, pairs: &[(StackVariable, StackVariable)], consume: bool) {
+        let mut pending = pairs.to_vec();
+        while !pending.is_empty() {
+            let next = (0..pending.len())
+                .min_by_key(|&i| self.get_offset(pending[i].0).max(self.get_offset(pending[i].1)))
+                .unwrap();
+            let (mut var1, mut var2) = pending.remove(next);
+            self.equals(&mut var1, consume, &mut var2, consume);
+        }
+    }
+
+    // bytes of the depths pushed to pick or roll the elements with equals_ordered(first, second) from the current offsets.
+    // the second variable is not moved when it's consumed and on top, otherwise it's one element deeper
+    // as the element of the first one is already on top
+    fn equals_cost(&self, first: StackVariable, second: StackVariable, consume_second: bool) -> u32 {
+        let depth_cost = |depth: u32| if depth <= 16 { 1 } else { 1 + encode_number(depth as i64).len() as u32 };
+        let size = first.size;
+        let second_offset = self.get_offset(second);
+        let first_cost = size * depth_cost(self.get_offset(first));
+        if consume_second && second_offset == 0 {
+            first_cost
+        } else {
+            first_cost + size * depth_cost(second_offset + 1)
+        }
+    }
+
+    fn equals_ordered(&mut self, var1: &mut StackVariable, consume_1: bool, var2: &mut StackVariable, consume_2: bool) {
         let dont_move = consume_2 && self.data.stack.last().unwrap().id == var2.id; 
 
         for i in 0..var1.size {
@@ -440,6 +1518,76 @@ impl StackTracker {
 
     }
 
+    // compares two variables consuming both. Unlike equals, that verifies every element with OP_EQUALVERIFY,
+    // the results of OP_EQUAL are accumulated with OP_BOOLAND and verified once at the end.
+    // it always moves both variables to the top and uses OP_SWAP and OP_ROLL to pair the elements
+    pub fn equals_and_verify(&mut self, var1: StackVariable, var2: StackVariable) {
+        assert_eq!(var1.size, var2.size, "The variables {:?} and {:?} are not the same size", var1, var2);
+        assert_ne!(var1.id, var2.id, "The variables {:?} and {:?} are the same", var1, var2);
+        self.move_var(var1);
+        self.move_var(var2);
+        let size = var1.size;
+        self.custom(script! {
+            { size }
+            OP_ROLL
+            OP_EQUAL
+            for i in 1..size {
+                OP_SWAP
+                { size - i + 1 }
+                OP_ROLL
+                OP_EQUAL
+                OP_BOOLAND
+            }
+            OP_VERIFY
+        }, 2, false, 0, "equals_and_verify");
+    }
+
+    // compares the top `count` elements of the stack with the top `count` elements of the altstack,
+    // consuming both. The expected value is usually staged with to_altstack so the elements are compared in order.
+    pub fn equalverify_from_altstack(&mut self, count: u32) {
+        // number of variables from the top that add up to `count` elements
+        let aligned = |vars: &[StackVariable], which: &str| {
+            let mut size = 0;
+            let mut n = 0;
+            for var in vars.iter().rev() {
+                if size >= count {
+                    break;
+                }
+                size += var.size;
+                n += 1;
+            }
+            assert_eq!(size, count, "The top {} elements of the {} are not aligned with the variables", count, which);
+            n
+        };
+        let on_stack = aligned(&self.data.stack, "stack");
+        let on_altstack = aligned(&self.data.altstack, "altstack");
+
+        for _ in 0..on_stack {
+            self.pop_consumed(false);
+        }
+        for _ in 0..on_altstack {
+            self.pop_consumed(true);
+        }
+
+        self.push_script(script! {
+            for i in 0..count {
+                OP_FROMALTSTACK
+                { count - i }
+                OP_ROLL
+                OP_EQUALVERIFY
+            }
+        }, "equalverify_from_altstack");
+    }
+
+    // compares and consumes the two variables on top of the stack
+    pub fn equalverify_adjacent(&mut self, var1: StackVariable, var2: StackVariable) {
+        assert_eq!(self.get_var_from_stack(1).id, var1.id, "The variable {:?} is not the second variable on the stack", var1);
+        assert_eq!(self.get_var_from_stack(0).id, var2.id, "The variable {:?} is not the top of the stack", var2);
+        let size = self.get_size(var1);
+        assert_eq!(size, self.get_size(var2), "The variables {:?} and {:?} are not the same size", var1, var2);
+        self.custom(equalverify_n(size), 2, false, 0, "equalverify_adjacent");
+    }
+
     pub fn get_offset(&self, var: StackVariable) -> u32 {
         let mut count = 0;
         for v in self.data.stack.iter().rev() {
@@ -448,12 +1596,20 @@ impl StackTracker {
             }
             count += v.size;
         }
+        self.assert_not_consumed(var);
+        self.assert_not_on_altstack(var);
         panic!("The var {:?} is not part of the stack", var);
     }
 
     pub fn get_var_from_stack(&self, depth: u32) -> StackVariable {
         self.data.stack[self.data.stack.len() - 1 - depth as usize]
     }
+
+    // variable at `depth` (in variables, 0 is the top), None if there are not enough variables
+    pub fn try_get_var_from_stack(&self, depth: u32) -> Option<StackVariable> {
+        let len = self.data.stack.len();
+        ((depth as usize) < len).then(|| self.data.stack[len - 1 - depth as usize])
+    }
     
     pub fn get_var_name(&self, var: StackVariable) -> String {
         self.data.names[&var.id].clone()
@@ -463,15 +1619,87 @@ impl StackTracker {
         self.script.len()
     }
 
+    #[cfg(feature = "std")]
     pub fn run(&self) -> StepResult {
         execute_step(self, self.script.len()-1)
     }
 
+    // like run but returns the failure as an error, so tests can use `stack.try_run()?`
+    #[cfg(feature = "std")]
+    pub fn try_run(&self) -> Result<StepResult, ScriptFailure> {
+        let result = self.run();
+        if result.success {
+            return Ok(result);
+        }
+        let last = self.script.len() - 1;
+        let (step, error_msg) = if result.error {
+            // the sizes are only known for the steps before the error
+            (execute_step_sizes(self).len().min(last), result.error_msg)
+        } else {
+            (last, "The script finished without a single true value on the stack".to_string())
+        };
+        Err(ScriptFailure {
+            step,
+            label: self.step_label(step).unwrap_or_default().to_string(),
+            last_opcode: result.last_opcode,
+            error_msg,
+        })
+    }
+
+    // runs the script in a transaction where dummy_signature() is valid for dummy_pubkey().
+    // allows to test scripts with OP_CHECKSIG without signing
+    #[cfg(feature = "std")]
+    pub fn run_with_dummy_sig(&self) -> StepResult {
+        execute_step_with_tx(self, self.script.len()-1, dummy_sig_tx_template())
+    }
+
+    // runs the script with a custom transaction (to check signatures and timelocks), context and options
+    // returning the state after each opcode, to see where the verification fails (see execute_trace)
+    #[cfg(feature = "std")]
+    pub fn run_with_trace(&self, tx_template: TxTemplate, ctx: ExecCtx, options: Options) -> Vec<StepResult> {
+        execute_trace(self, tx_template, ctx, options)
+    }
+
+    // bytes of the witness needed to spend the script through the taproot script path: the input elements given with
+    // from_witness, the script and the control block, with their length prefixes.
+    // Assumes a tree with a single leaf (33 bytes of control block, each level of a bigger tree adds 32) and no annex.
+    // Only the elements of the witness layout and the placeholders are counted as the variables created with `define` have no known size
+    pub fn estimated_witness_size(&self) -> usize {
+        let mut elements = self.witness.clone();
+        elements.push(self.get_script().into_bytes());
+        elements.push(vec![0; TAPROOT_CONTROL_BASE_SIZE]);
+        Witness::from_slice(&elements).size()
+    }
+
+    // defines a witness input of `len` bytes (64 for a schnorr signature, 0 for a missing one) to estimate the size
+    // of the witness without signing. The placeholder is filled with zeros, so the signature checks fail when running it
+    pub fn sig_placeholder(&mut self, len: usize) -> StackVariable {
+        assert!(self.script.is_empty(), "The placeholders are part of the witness, they need to be defined before emitting any script");
+        self.witness.push(vec![0; len]);
+        self.define(1, &format!("sig_placeholder({})", len))
+    }
+
+    // script and witness formatted to be pasted as arguments of btcdeb
+    #[cfg(feature = "std")]
+    pub fn to_btcdeb_script(&self) -> String {
+        btcdeb_script(&self.get_script(), &self.witness)
+    }
+
+    // runs the script and returns the elements left on the altstack (from bottom to top)
+    #[cfg(feature = "std")]
+    pub fn final_altstack(&self) -> Vec<Vec<u8>> {
+        let (exec, _) = debug_script_with_witness(self.get_script(), self.witness.clone());
+        let altstack = exec.altstack();
+        (0..altstack.len()).map(|i| altstack.get(i)).collect()
+    }
+
  
+    #[cfg(feature = "std")]
     pub fn show_stack(&self) {
         show_stack(&self.data, vec![]);
     }
 
+    #[cfg(feature = "std")]
     pub fn show_altstack(&self) {
         show_altstack(&self.data, vec![]);
     }
@@ -479,13 +1707,15 @@ impl StackTracker {
     pub fn copy_var_sub_n(&mut self, var: StackVariable, n: u32) -> StackVariable {
         let offset = self.get_offset(var);
         let var = self.get_var(offset);
+        assert!(var.size > n, "The variable {:?} is not big enough to copy n={}", var, n);
         let offset_n = offset + var.size - 1 - n;
         let name = self.get_var_name(var);
 
         let new_var = StackVariable::new(self.next_counter(), 1);
-        self.rename(new_var, &format!("copy_{}[{}]", name, n));
+        self.data.mark_used(var);
+        self.data.set_name(new_var, &format!("copy_{}[{}]", name, n));
         self.push(new_var);
-        self.push_script( copy_from(offset_n, 1));
+        self.push_script( copy_from(offset_n, 1), "copy_var_sub_n");
         new_var
     }
 
@@ -498,6 +1728,7 @@ impl StackTracker {
 
         user_var.size -= 1;
         var.size -= 1;
+        self.data.mark_used(var);
         
         self.data.decrease_size(var);
 
@@ -506,9 +1737,9 @@ impl StackTracker {
         }
 
         let new_var = StackVariable::new(self.next_counter(), 1);
-        self.rename(new_var, &format!("{}[{}]", name, n));
+        self.data.set_name(new_var, &format!("{}[{}]", name, n));
         self.push(new_var);
-        self.push_script( move_from(offset_n, 1));
+        self.push_script( move_from(offset_n, 1), "move_var_sub_n");
         new_var
     }
 
@@ -518,31 +1749,100 @@ impl StackTracker {
                 return i;
             }
         }
+        self.assert_not_consumed(var);
+        self.assert_not_on_altstack(var);
         panic!("The var {:?} is not part of the stack", var);
     }
 
+    // true if join can merge the variable: it is on the stack (not on the altstack or consumed), it is not
+    // the one on top and its size is the current one
+    pub fn can_join(&self, var: StackVariable) -> bool {
+        let len = self.data.stack.len();
+        self.data.stack.iter().position(|v| v.id == var.id)
+            .is_some_and(|i| i + 1 < len && self.data.stack[i].size == var.size)
+    }
+
+    // merges the variable with the one immediately above it, var1 keeps its id and name.
+    // the numbers are pushed from the most significant element, so var1 becomes the most significant part
+    // (i.e. joining number_u16(0x1234) with number_u16(0x5678) above it is number_u32(0x12345678))
     pub fn join(&mut self, var1: &mut StackVariable) {
 
         let len = self.data.stack.len();
         let i = self.get_index_var(*var1);
-        assert!(i + 1 < len, "The variable {:?} is the last one on the stack, can't join.", var1);
+        assert!(i + 1 < len, "The variable {:?} ({}) is on top of the stack, there is no variable above to join. \
+            Push the part to join after it or use join_be to place them", var1, self.get_var_name(*var1));
+        assert_eq!(self.data.stack[i].size, var1.size, "The variable {:?} is outdated, the stack has it with size {}", var1, self.data.stack[i].size);
 
         let next_size = self.data.stack[i+1].size;
         var1.size += next_size;
         self.data.increase_size(i, next_size);
 
-        self.remove_var(self.data.stack[i+1]);
+        let absorbed = self.data.stack[i+1];
+        self.data.mark_used(absorbed);
+        self.remove_var(absorbed);
+        self.transfer_bindings(absorbed, *var1);
+    }
+
+    // joins both variables so `high` is the most significant part, moving them to the top if they are not
+    // already adjacent in that order. `high` keeps its id and name
+    pub fn join_be(&mut self, mut high: StackVariable, low: StackVariable) -> StackVariable {
+        self.place_adjacent(high, low);
+        self.join(&mut high);
+        high
+    }
+
+    // joins both variables so `low` is the least significant part (the same as join_be(high, low)). `low` keeps its id and name
+    pub fn join_le(&mut self, mut low: StackVariable, high: StackVariable) -> StackVariable {
+        self.place_adjacent(high, low);
+        self.join_left(&mut low);
+        low
+    }
+
+    // leaves `low` immediately above `high`
+    fn place_adjacent(&mut self, high: StackVariable, low: StackVariable) {
+        let i = self.get_index_var(high);
+        let j = self.get_index_var(low);
+        if i + 1 == j {
+            return;
+        }
+        if j + 1 == i && i == self.data.stack.len() - 1 {
+            self.move_var(low);
+        } else {
+            self.move_var(high);
+            self.move_var(low);
+        }
+    }
+
+    // merges the variable with the one immediately below it, var keeps its id and name
+    pub fn join_left(&mut self, var: &mut StackVariable) {
+        let i = self.get_index_var(*var);
+        assert!(i > 0, "The variable {:?} is the first one on the stack, there is no variable below to join.", var);
+        assert_eq!(self.data.stack[i].size, var.size, "The variable {:?} is outdated, the stack has it with size {}", var, self.data.stack[i].size);
+
+        let prev_size = self.data.stack[i-1].size;
+        var.size += prev_size;
+        self.data.increase_size(i, prev_size);
+
+        let absorbed = self.data.stack[i-1];
+        self.data.mark_used(absorbed);
+        self.remove_var(absorbed);
+        self.transfer_bindings(absorbed, *var);
     }
 
     pub fn get_var(&self, depth: u32) -> StackVariable {
+        self.try_get_var(depth).unwrap_or_else(|| panic!("The depth {} is not valid", depth))
+    }
+
+    // variable that starts at `depth` (in elements), None if the depth is out of the stack or in the middle of a variable
+    pub fn try_get_var(&self, depth: u32) -> Option<StackVariable> {
         let mut count = 0;
         for v in self.data.stack.iter().rev() {
             if count == depth {
-                return *v;
+                return Some(*v);
             }
             count += v.size;
         }
-        panic!("The depth {} is not valid", depth);
+        None
     }
 
     pub fn join_count(&mut self, var: &mut StackVariable, count: u32) -> StackVariable {
@@ -562,13 +1862,17 @@ impl StackTracker {
 
     pub fn explode(&mut self, var: StackVariable) -> Vec<StackVariable> {
         let mut ret = Vec::new();
+        // the name needs to be read before removing the variable as remove_var also drops it from names
         let off = self.get_index_var(var);
         let name = self.get_var_name(var);
         let size = self.get_size(var);
-        self.remove_var(var);
+        self.data.mark_used(var);
+        self.consume(var);
+        self.data.remove_var(var);
+        self.data.remove_name(var);
         for i in 0..size {
             let new_var = StackVariable::new(self.next_counter(), 1);
-            self.rename(new_var, &format!("{}[{}]", name, i));
+            self.data.set_name(new_var, &format!("{}[{}]", name, i));
             ret.push(new_var);
             self.data.insert_var(off + i as usize, new_var);
         }
@@ -577,19 +1881,21 @@ impl StackTracker {
     }
 
     pub fn custom_ex(&mut self, script: Script, consumes: u32, output_vars: Vec<(u32, String )> , to_altstack: u32) -> Vec<StackVariable> {
+        self.custom_ex_labeled(script, consumes, output_vars, to_altstack, "custom_ex")
+    }
+
+    fn custom_ex_labeled(&mut self, script: Script, consumes: u32, output_vars: Vec<(u32, String )> , to_altstack: u32, label: &str) -> Vec<StackVariable> {
 
         for _ in 0..consumes {
-            self.data.pop_stack();
+            let var = self.data.pop_stack();
+            self.data.mark_used(var);
+            self.consume(var);
         }
 
-        if output_vars.len() > 0 {
-            let mut ret = Vec::new();
-
-            for (size, name) in output_vars {
-                ret.push(self.define(size, &name));
-            }
-            self.push_script(script);
-            return ret;
+        // the script can leave outputs on both stacks, only the ones of the main stack are returned
+        let mut ret = Vec::new();
+        for (size, name) in output_vars {
+            ret.push(self.define(size, &name));
         }
 
         for _ in 0..to_altstack {
@@ -597,8 +1903,8 @@ impl StackTracker {
             self.data.push_altstack(StackVariable::new(c, 1));
         }
 
-        self.push_script(script);
-        vec![]
+        self.push_script(script, label);
+        ret
     }
 
     pub fn custom(&mut self, script: Script, consumes: u32, output: bool, to_altstack: u32, name: &str ) -> Option<StackVariable> {
@@ -606,7 +1912,7 @@ impl StackTracker {
         if output {
             output_vec.push((1 as u32, name.to_string()));
         }
-        let ret = self.custom_ex(script, consumes, output_vec, to_altstack);
+        let ret = self.custom_ex_labeled(script, consumes, output_vec, to_altstack, name);
         if ret.len() == 0 {
             None
         } else {
@@ -614,12 +1920,84 @@ impl StackTracker {
         }
     }
 
+    // reorders the whole stack to `desired_order` (from the bottom to the top) so the following operations are cheaper.
+    // the longest start of the desired order that is already in that relative order is kept in place and
+    // the rest of the variables are moved to the top one by one (one roll per variable)
+    pub fn compact(&mut self, desired_order: &[StackVariable]) {
+        let mut current = self.data.stack.iter().map(|v| v.id).collect::<Vec<u32>>();
+        let mut desired = desired_order.iter().map(|v| v.id).collect::<Vec<u32>>();
+        current.sort();
+        desired.sort();
+        assert_eq!(current, desired, "The desired order needs to contain every variable of the stack once");
+
+        let mut keep = 0;
+        for var in self.data.stack.iter() {
+            if keep < desired_order.len() && var.id == desired_order[keep].id {
+                keep += 1;
+            }
+        }
+        for var in desired_order[keep..].iter() {
+            self.move_var(*var);
+        }
+    }
+
+    // reorders the top variables (all of them of size 1) with the minimum amount of rolls.
+    // uses the same permutation format as custom_reorder
+    pub fn permute_top(&mut self, permutation: &[usize]) {
+        for depth in 0..permutation.len() {
+            let var = self.get_var_from_stack(depth as u32);
+            assert_eq!(var.size, 1, "The variable {:?} at depth {} needs to be of size 1 to be permuted", var, depth);
+        }
+        self.custom_reorder(permute(permutation), permutation, "permute_top");
+    }
+
+    // custom script that reorders the top variables of the stack.
+    // permutation[i] is the depth (in variables, 0 is the top) that had before the script the variable that ends at depth i
+    pub fn custom_reorder(&mut self, script: Script, permutation: &[usize], name: &str) {
+        let mut sorted = permutation.to_vec();
+        sorted.sort();
+        assert!(sorted.iter().enumerate().all(|(i, p)| i == *p), "{:?} is not a valid permutation", permutation);
+
+        let before = (0..permutation.len()).map(|_| self.data.pop_stack()).collect::<Vec<StackVariable>>();
+        for depth in permutation.iter().rev() {
+            self.data.push_stack(before[*depth]);
+        }
+        self.push_script(script, name);
+    }
+
     pub fn reverse_u32(&mut self, var: StackVariable) {
         let size = self.get_size(var);
-        if size != 8 {
-            panic!("The variable {:?} is not 8 elements long", var);
+        if size != self.u32_size() {
+            panic!("The variable {:?} is not {} elements long", var, self.u32_size());
         }
-        self.custom(reverse_u32(), 0, false, 0, "");
+        self.data.mark_used(var);
+        let script = match self.base {
+            Base::Nibble => reverse_u32(),
+            Base::Byte => reverse_u32_bytes(),
+        };
+        self.custom(script, 0, false, 0, "reverse_u32");
+    }
+
+    // consumes the u32 returning its two's complement negation ((2^32 - x) mod 2^32), with the same representation
+    // (most significant element deepest, nibbles or bytes depending on the base)
+    pub fn negate_u32(&mut self, var: StackVariable) -> StackVariable {
+        let size = self.get_size(var);
+        assert_eq!(size, self.u32_size(), "The variable {:?} is not {} elements long", var, self.u32_size());
+        let name = format!("negate({})", self.get_var_name(var));
+        self.move_var(var);
+        self.custom_ex_labeled(negate_base(size, self.base), 1, vec![(size, name)], 0, "negate_u32")[0]
+    }
+
+    // sorts the top `count` numbers (of size 1) in ascending order, leaving the biggest on top.
+    // as the order depends on the values, the variables are consumed and new ones are returned
+    pub fn sort_top(&mut self, count: u32) -> Vec<StackVariable> {
+        assert!(count <= MAX_SORT_SIZE, "sort_top supports up to {} elements", MAX_SORT_SIZE);
+        for i in 0..count {
+            let var = self.get_var_from_stack(i);
+            assert_eq!(var.size, 1, "The variable {:?} needs to be of size 1 to be sorted", var);
+        }
+        let output = (0..count).map(|i| (1, format!("sorted[{}]", i))).collect();
+        self.custom_ex_labeled(sort_top(count), count, output, 0, "sort_top")
     }
 
     fn op(&mut self, op: Opcode, consumes: u32, output: bool, name: &str ) -> Option<StackVariable> {
@@ -628,52 +2006,132 @@ impl StackTracker {
         self.custom(s, consumes, output, 0, name)
     }
 
+    fn mark_top_used(&mut self, count: u32) {
+        for depth in 0..count {
+            let var = self.get_var_from_stack(depth);
+            self.data.mark_used(var);
+        }
+    }
+
+    // numeric operations work over single element variables, otherwise the model would get out of sync
+    fn assert_numbers(&self, count: u32, name: &str) {
+        for depth in 0..count {
+            let var = self.get_var_from_stack(depth);
+            assert_eq!(var.size, 1, "{} expects a number but the variable {:?} at depth {} has {} elements", name, var, depth, var.size);
+        }
+    }
+
+    // the stack opcodes work over single element variables, `alternative` is suggested for bigger variables
+    fn assert_single_elements(&self, count: u32, name: &str, alternative: &str) {
+        for depth in 0..count {
+            let var = self.get_var_from_stack(depth);
+            assert_eq!(var.size, 1, "{} requires elements of size 1 but the variable {:?} at depth {} has {} elements, use {} instead", name, var, depth, var.size, alternative);
+        }
+    }
+
+    fn numeric_op(&mut self, op: Opcode, consumes: u32, output: bool, name: &str) -> Option<StackVariable> {
+        self.assert_numbers(consumes, name);
+        self.op(op, consumes, output, name)
+    }
+
+    // name of the result of an operation over the two variables on top of the stack (the deeper one is `a`).
+    // None if any of them has no name
+    fn operands_name(&self, f: impl Fn(&str, &str) -> String) -> Option<String> {
+        let a = self.try_get_var_from_stack(1)?;
+        let b = self.try_get_var_from_stack(0)?;
+        // long operand names are abbreviated so the names don't grow with every chained operation
+        let short = |name: &str| if name.len() > MAX_OPERAND_NAME_LEN { "\u{2026}".to_string() } else { name.to_string() };
+        match (self.data.get_name(a), self.data.get_name(b)) {
+            (Some(a), Some(b)) if !a.is_empty() && !b.is_empty() => Some(f(&short(a), &short(b))),
+            _ => None,
+        }
+    }
+
+    // the label of the step keeps the generic name, the result takes the one derived from the operands if there is one
+    fn named_result(&mut self, var: StackVariable, name: Option<String>) -> StackVariable {
+        if let Some(name) = name {
+            self.data.set_name(var, &name);
+            // the rename belongs to the step of the operation
+            if let Some(last) = self.history.last_mut() {
+                *last = self.data.redo_log.len() as u32;
+            }
+        }
+        var
+    }
+
+    fn infix_op(&mut self, op: Opcode, label: &str, symbol: &str) -> StackVariable {
+        self.assert_numbers(2, label);
+        let name = self.operands_name(|a, b| format!("({} {} {})", a, symbol, b));
+        let var = self.numeric_op(op, 2, true, label).unwrap();
+        self.named_result(var, name)
+    }
+
     pub fn op_negate(&mut self) -> StackVariable {
-        self.op(OP_NEGATE, 1, true, "OP_NEGATE()").unwrap()
+        self.numeric_op(OP_NEGATE, 1, true, "OP_NEGATE()").unwrap()
     }
 
     pub fn op_abs(&mut self) -> StackVariable {
-        self.op(OP_ABS, 1, true, "OP_ABS()").unwrap()
+        self.numeric_op(OP_ABS, 1, true, "OP_ABS()").unwrap()
     }
 
     pub fn op_add(&mut self) -> StackVariable {
-        self.op(OP_ADD, 2, true, "OP_ADD()").unwrap()
+        self.infix_op(OP_ADD, "OP_ADD()", "+")
     }
 
     pub fn op_sub(&mut self) -> StackVariable {
-        self.op(OP_SUB, 2, true, "OP_SUB()").unwrap()
+        self.infix_op(OP_SUB, "OP_SUB()", "-")
+    }
+
+    // the result of an arithmetic operation can take 5 bytes, failing later when used as operand.
+    // the checked versions verify that the result fits in 4 bytes at the cost of 4 extra opcodes
+    pub fn op_add_checked(&mut self) -> StackVariable {
+        self.assert_numbers(2, "OP_ADD()");
+        let name = self.operands_name(|a, b| format!("({} + {})", a, b));
+        let var = self.custom(script!{ OP_ADD { check_number_size() } }, 2, true, 0, "OP_ADD()").unwrap();
+        self.named_result(var, name)
+    }
+
+    pub fn op_sub_checked(&mut self) -> StackVariable {
+        self.assert_numbers(2, "OP_SUB()");
+        let name = self.operands_name(|a, b| format!("({} - {})", a, b));
+        let var = self.custom(script!{ OP_SUB { check_number_size() } }, 2, true, 0, "OP_SUB()").unwrap();
+        self.named_result(var, name)
     }
 
     pub fn op_min(&mut self) -> StackVariable {
-        self.op(OP_MIN, 2, true, "OP_MIN()").unwrap()
+        let name = self.operands_name(|a, b| format!("min({}, {})", a, b));
+        let var = self.numeric_op(OP_MIN, 2, true, "OP_MIN()").unwrap();
+        self.named_result(var, name)
     }
 
     pub fn op_max(&mut self) -> StackVariable {
-        self.op(OP_MAX, 2, true, "OP_MAX()").unwrap()
+        let name = self.operands_name(|a, b| format!("max({}, {})", a, b));
+        let var = self.numeric_op(OP_MAX, 2, true, "OP_MAX()").unwrap();
+        self.named_result(var, name)
     }
 
     pub fn op_within(&mut self) -> StackVariable {
-        self.op(OP_WITHIN, 3, true, "OP_WITHIN()").unwrap()
+        self.numeric_op(OP_WITHIN, 3, true, "OP_WITHIN()").unwrap()
     }
 
     pub fn op_1add(&mut self) -> StackVariable {
-        self.op(OP_1ADD, 1, true, "OP_1ADD()").unwrap()
+        self.numeric_op(OP_1ADD, 1, true, "OP_1ADD()").unwrap()
     }
 
     pub fn op_1sub(&mut self) -> StackVariable {
-        self.op(OP_1SUB, 1, true, "OP_1SUB()").unwrap()
+        self.numeric_op(OP_1SUB, 1, true, "OP_1SUB()").unwrap()
     }
 
     pub fn op_not(&mut self) -> StackVariable {
-        self.op(OP_NOT, 1, true, "OP_NOT()").unwrap()
+        self.numeric_op(OP_NOT, 1, true, "OP_NOT()").unwrap()
     }
 
     pub fn op_booland(&mut self) -> StackVariable {
-        self.op(OP_BOOLAND, 2, true, "OP_BOOLAND()").unwrap()
+        self.infix_op(OP_BOOLAND, "OP_BOOLAND()", "&&")
     }
 
     pub fn op_boolor(&mut self) -> StackVariable {
-        self.op(OP_BOOLOR, 2, true, "OP_BOOLOR()").unwrap()
+        self.infix_op(OP_BOOLOR, "OP_BOOLOR()", "||")
     }
 
     pub fn op_equal(&mut self) -> StackVariable {
@@ -681,35 +2139,112 @@ impl StackTracker {
     }
 
     pub fn op_numequal(&mut self) -> StackVariable {
-        self.op(OP_NUMEQUAL, 2, true, "OP_NUMEQUAL()").unwrap()
+        self.infix_op(OP_NUMEQUAL, "OP_NUMEQUAL()", "==")
     }
 
     pub fn op_numnotequal(&mut self) -> StackVariable {
-        self.op(OP_NUMNOTEQUAL, 2, true, "OP_NUMNOTEQUAL()").unwrap()
+        self.infix_op(OP_NUMNOTEQUAL, "OP_NUMNOTEQUAL()", "!=")
     }
 
     pub fn op_lessthan(&mut self) -> StackVariable {
-        self.op(OP_LESSTHAN, 2, true, "OP_LESSTHAN()").unwrap()
+        self.infix_op(OP_LESSTHAN, "OP_LESSTHAN()", "<")
     }
 
     pub fn op_lessthanorequal(&mut self) -> StackVariable {
-        self.op(OP_LESSTHANOREQUAL, 2, true, "OP_LESSTHANOREQUAL()").unwrap()
+        self.infix_op(OP_LESSTHANOREQUAL, "OP_LESSTHANOREQUAL()", "<=")
     }
 
     pub fn op_greaterthan(&mut self) -> StackVariable {
-        self.op(OP_GREATERTHAN, 2, true, "OP_GREATERTHAN()").unwrap()
+        self.infix_op(OP_GREATERTHAN, "OP_GREATERTHAN()", ">")
     }
 
     pub fn op_greaterthanorequal(&mut self) -> StackVariable {
-        self.op(OP_GREATERTHANOREQUAL, 2, true, "OP_GREATERTHANOREQUAL()").unwrap()
+        self.infix_op(OP_GREATERTHANOREQUAL, "OP_GREATERTHANOREQUAL()", ">=")
+    }
+
+    fn compare_const(&mut self, op: Opcode, k: i64, name: &str) -> StackVariable {
+        self.assert_numbers(1, name);
+        let mut s = script!{ { k } };
+        s.push_opcode(op);
+        self.custom(s, 1, true, 0, &format!("{}({})", name, k)).unwrap()
+    }
+
+    pub fn lt_const(&mut self, k: i64) -> StackVariable {
+        self.compare_const(OP_LESSTHAN, k, "lt_const")
+    }
+
+    pub fn le_const(&mut self, k: i64) -> StackVariable {
+        self.compare_const(OP_LESSTHANOREQUAL, k, "le_const")
+    }
+
+    pub fn gt_const(&mut self, k: i64) -> StackVariable {
+        self.compare_const(OP_GREATERTHAN, k, "gt_const")
+    }
+
+    pub fn ge_const(&mut self, k: i64) -> StackVariable {
+        self.compare_const(OP_GREATERTHANOREQUAL, k, "ge_const")
+    }
+
+    pub fn eq_const(&mut self, k: i64) -> StackVariable {
+        self.compare_const(OP_NUMEQUAL, k, "eq_const")
+    }
+
+    // checks if the number on top of the stack is one of the values (OP_DUP k OP_NUMEQUAL for the first one and
+    // OP_OVER k OP_NUMEQUAL OP_BOOLOR for the rest). The number is consumed if `consume` is set
+    pub fn is_member(&mut self, values: &[i64], consume: bool) -> StackVariable {
+        assert!(!values.is_empty(), "is_member requires at least one value");
+        self.assert_numbers(1, "is_member");
+        let script = script! {
+            OP_DUP { values[0] } OP_NUMEQUAL
+            for k in values[1..].iter() {
+                OP_OVER { *k } OP_NUMEQUAL OP_BOOLOR
+            }
+            if consume {
+                OP_NIP
+            }
+        };
+        self.custom(script, consume as u32, true, 0, "is_member").unwrap()
+    }
+
+    // evaluates the polynomial with the coefficients from the highest degree to the constant term
+    // (i.e. [3, 2, 1] is 3x^2 + 2x + 1) using Horner's method. x is consumed and the result is returned.
+    // As OP_MUL is disabled x is split in HORNER_X_BITS bits and each product is done by doubling, so x needs to be
+    // in [0, 2^HORNER_X_BITS) or the script fails. The arithmetic opcodes only accept 4 bytes numbers, so every
+    // partial result (and the products acc * x) need to be in (-2^31, 2^31) for the script to succeed
+    pub fn horner_eval(&mut self, coeffs: &[i64], x: StackVariable) -> StackVariable {
+        assert!(!coeffs.is_empty(), "horner_eval requires at least one coefficient");
+        assert_eq!(x.size, 1, "horner_eval expects x to be a number but {:?} has {} elements", x, x.size);
+        self.move_var(x);
+        let name = self.get_var_name(x);
+        self.custom_ex_labeled(number_to_bits(HORNER_X_BITS), 1, vec![(HORNER_X_BITS, format!("bits({})", name))], 0, "horner_x_bits");
+        self.number_exact(coeffs[0]);
+        for c in coeffs[1..].iter() {
+            self.custom(mul_by_bits(HORNER_X_BITS), 1, true, 0, "horner_mul");
+            if *c != 0 {
+                self.number_exact(*c);
+                self.op_add();
+            }
+        }
+        self.custom(script!{ OP_TOALTSTACK { drop_count(HORNER_X_BITS) } OP_FROMALTSTACK }, 2, true, 0, "horner_eval").unwrap()
+    }
+
+    // checks that the top element is in the range [lo, hi)
+    pub fn within_const(&mut self, lo: i64, hi: i64) -> StackVariable {
+        self.assert_numbers(1, "within_const");
+        self.custom(script!{ { lo } { hi } OP_WITHIN }, 1, true, 0, &format!("within_const({}, {})", lo, hi)).unwrap()
+    }
+
+    pub fn within_const_verify(&mut self, lo: i64, hi: i64) {
+        self.assert_numbers(1, "within_const_verify");
+        self.custom(script!{ { lo } { hi } OP_WITHIN OP_VERIFY }, 1, false, 0, &format!("within_const_verify({}, {})", lo, hi));
     }
 
     pub fn op_numequalverify(&mut self) {
-        self.op(OP_NUMEQUALVERIFY, 2, false, "OP_NUMEQUALVERIFY()");
+        self.numeric_op(OP_NUMEQUALVERIFY, 2, false, "OP_NUMEQUALVERIFY()");
     }
 
     pub fn op_0notequal(&mut self) -> StackVariable {
-        self.op(OP_0NOTEQUAL, 1, true, "OP_0NOTEQUAL()").unwrap()
+        self.numeric_op(OP_0NOTEQUAL, 1, true, "OP_0NOTEQUAL()").unwrap()
     }
 
     pub fn op_pick(&mut self) -> StackVariable {
@@ -734,6 +2269,7 @@ impl StackTracker {
     }
 
     pub fn op_2swap(&mut self) {
+        self.assert_single_elements(4, "OP_2SWAP", "move_var");
         let d = self.data.pop_stack();
         let c = self.data.pop_stack();
         let b = self.data.pop_stack();
@@ -752,12 +2288,13 @@ impl StackTracker {
         let x = self.data.pop_stack();
         let y = self.data.pop_stack();
         assert!(x.size == 1 && y.size == 1, "OP_TUCK requires two elements of size 1");
+        self.data.mark_used(x);
 
         self.push(var);
         self.push(y);
         self.push(x);
         self.data.set_name(var, "OP_TuCK()");
-        self.push_script(script!{OP_TUCK});
+        self.push_script(script!{OP_TUCK}, "OP_TUCK()");
         var
 
     }
@@ -774,6 +2311,7 @@ impl StackTracker {
     }
 
     pub fn op_2rot(&mut self) {
+        self.assert_single_elements(6, "OP_2ROT", "move_var");
         let f = self.data.pop_stack();
         let e = self.data.pop_stack();
         let d = self.data.pop_stack();
@@ -792,15 +2330,19 @@ impl StackTracker {
 
     pub fn op_over(&mut self) -> StackVariable {
         let x = self.get_var_from_stack(1);
+        self.data.mark_used(x);
         let name = self.get_var_name(x);
         self.op(OP_OVER, 0, true, &name).unwrap()
     }
     
     pub fn op_2over(&mut self) -> (StackVariable, StackVariable) {
+        self.assert_single_elements(4, "OP_2OVER", "copy_var");
         let x = self.get_var_from_stack(3);
         let name = self.get_var_name(x);
         let y = self.get_var_from_stack(2);
         let namey = self.get_var_name(y);
+        self.data.mark_used(x);
+        self.data.mark_used(y);
         self.define(1, &name);
         (x, self.op(OP_2OVER, 0, true, &namey).unwrap())
     }
@@ -813,12 +2355,31 @@ impl StackTracker {
         let _ = self.op(OP_EQUALVERIFY, 2, false, "OP_EQUALVERIFY()");
     }
 
+    pub fn op_size(&mut self) -> StackVariable {
+        self.mark_top_used(1);
+        self.op(OP_SIZE, 0, true, "OP_SIZE()").unwrap()
+    }
+
+    // asserts that the top element is exactly `expected` bytes long, leaving it on the stack
+    pub fn op_size_verify(&mut self, expected: u32) {
+        self.mark_top_used(1);
+        self.custom(script!{ OP_SIZE { expected } OP_EQUALVERIFY }, 0, false, 0, "size_verify");
+    }
+
     pub fn op_sha256(&mut self) -> StackVariable {
         let x = self.get_var_from_stack(0);
         let name = self.get_var_name(x);
         self.op(OP_SHA256, 1, true, &format!("sha256({})",name)).unwrap()
     }
 
+    // hashes a variable of size 1 wherever it is on the stack. The variable is consumed and the digest is left on top.
+    // tapscript can not concatenate elements, so data of more than one element needs to be pushed as a single element
+    pub fn op_sha256_var(&mut self, var: StackVariable) -> StackVariable {
+        assert_eq!(var.size, 1, "op_sha256_var hashes a single element and {:?} has {} elements", var, var.size);
+        self.move_var(var);
+        self.op_sha256()
+    }
+
     pub fn op_hash160(&mut self) -> StackVariable {
         let x = self.get_var_from_stack(0);
         let name = self.get_var_name(x);
@@ -837,11 +2398,133 @@ impl StackTracker {
         self.op(OP_RIPEMD160, 1, true, &format!("ripemd160({})",name)).unwrap()
     }
 
+    // expects the signature below the pubkey
+    pub fn op_checksig(&mut self) -> StackVariable {
+        self.op(OP_CHECKSIG, 2, true, "OP_CHECKSIG()").unwrap()
+    }
+
+    pub fn op_checksigverify(&mut self) {
+        self.op(OP_CHECKSIGVERIFY, 2, false, "OP_CHECKSIGVERIFY()");
+    }
+
+    // one level of a merkle path: hashes the node on top of the stack with the sibling as sha256(left || right).
+    // `left` tells if the sibling is the left child.
+    // WARNING: tapscript has no way to hash two elements without concatenating them, so this uses OP_CAT (BIP-347)
+    // which is NOT active on bitcoin: 0x7e is OP_SUCCESS126 in tapscript and any leaf that contains it can be spent
+    // by anyone (assert_tapscript_valid rejects it). It's only available with the `op_cat` feature, for chains or
+    // executors where OP_CAT is enabled (as the one used by the debugger)
+    #[cfg(feature = "op_cat")]
+    pub fn merkle_step(&mut self, sibling: StackVariable, left: bool) -> StackVariable {
+        let node = self.get_var_from_stack(0);
+        assert_ne!(node.id, sibling.id, "The sibling {:?} can not be the node on top of the stack", sibling);
+        assert!(node.size == 1 && sibling.size == 1, "The node {:?} and the sibling {:?} need to be of size 1", node, sibling);
+        self.move_var(sibling);
+        let script = if left {
+            script!{ OP_SWAP OP_CAT OP_SHA256 }
+        } else {
+            script!{ OP_CAT OP_SHA256 }
+        };
+        self.custom(script, 2, true, 0, "merkle_node").unwrap()
+    }
+
+    // consumes the three variables returning if_true when the selector is not zero and if_false otherwise.
+    // single element values are selected without branching with OP_NOT OP_PICK (the selector is the depth to pick),
+    // bigger ones with an OP_IF that drops the one not selected (after OP_0NOTEQUAL, as tapscript only accepts 0 or 1 on OP_IF)
+    pub fn select(&mut self, selector: StackVariable, if_true: StackVariable, if_false: StackVariable) -> StackVariable {
+        assert_eq!(selector.size(), 1, "The selector {:?} needs to be a single element", selector);
+        let size = if_true.size();
+        assert_eq!(size, if_false.size(), "The variables {:?} and {:?} are not the same size", if_true, if_false);
+        let len = self.data.stack.len();
+        let in_place = len >= 3 && self.data.stack[len - 3..].iter().map(|v| v.id).eq([if_false.id, if_true.id, selector.id]);
+        if !in_place {
+            self.move_var(if_false);
+            self.move_var(if_true);
+            self.move_var(selector);
+        }
+        let script = if size == 1 {
+            script! { OP_NOT OP_PICK OP_NIP OP_NIP }
+        } else {
+            script! {
+                OP_0NOTEQUAL
+                OP_IF
+                    for _ in 0..size {
+                        { size }
+                        OP_ROLL
+                        OP_DROP
+                    }
+                OP_ELSE
+                    { drop_count(size) }
+                OP_ENDIF
+            }
+        };
+        self.custom_ex_labeled(script, 3, vec![(size, "select".to_string())], 0, "select")[0]
+    }
+
+    // k-of-n multisig for tapscript using OP_CHECKSIGADD.
+    // Expects one signature per pubkey on top of the stack in reverse order (the signature for pubkeys[0] on top),
+    // using an empty element for the missing signatures. Consumes the signatures and outputs the result of the check.
+    pub fn checksigadd_threshold(&mut self, pubkeys: &[Vec<u8>], threshold: u32) -> StackVariable {
+        assert!(!pubkeys.is_empty(), "checksigadd_threshold requires at least one pubkey");
+        let script = script! {
+            OP_0
+            for pubkey in pubkeys.iter() {
+                { pubkey.clone() }
+                OP_CHECKSIGADD
+            }
+            { threshold }
+            OP_NUMEQUAL
+        };
+        self.custom(script, pubkeys.len() as u32, true, 0, &format!("checksigadd({}-of-{})", threshold, pubkeys.len())).unwrap()
+    }
+
+    // consumes the preimage on top of the stack verifying that its hash160 is the expected digest
+    pub fn assert_hash160_preimage(&mut self, expected_digest: &[u8]) {
+        assert_eq!(expected_digest.len(), 20, "hash160 digest needs to be 20 bytes long");
+        self.custom(script!{ OP_HASH160 { expected_digest.to_vec() } OP_EQUALVERIFY }, 1, false, 0, "assert_hash160_preimage");
+    }
+
+    // consumes the preimage on top of the stack verifying that its sha256 is the expected digest
+    pub fn assert_sha256_preimage(&mut self, expected_digest: &[u8]) {
+        assert_eq!(expected_digest.len(), 32, "sha256 digest needs to be 32 bytes long");
+        self.custom(script!{ OP_SHA256 { expected_digest.to_vec() } OP_EQUALVERIFY }, 1, false, 0, "assert_sha256_preimage");
+    }
+
+    // hash-lock branch of an htlc: OP_SIZE <32> OP_EQUALVERIFY OP_HASH160 <hash> OP_EQUALVERIFY.
+    // consumes the 32 bytes preimage on top of the stack, leaving the elements below it (i.e. the signature)
+    // on top for the caller to continue with the signature check
+    pub fn htlc(&mut self, payment_hash: &[u8]) {
+        assert_eq!(payment_hash.len(), 20, "hash160 payment hash needs to be 20 bytes long");
+        self.op_size_verify(32);
+        self.assert_hash160_preimage(payment_hash);
+    }
+
     pub fn hexstr(&mut self, value: &str) -> StackVariable {
         let bytes = Vec::from_hex(value).unwrap();
         self.var(1, script!{{bytes}}, "hexdata")
     }
 
+    // pushes a constant that is going to be reused with use_constant.
+    // it's meant to be called at the beginning so the constant stays at the bottom of the stack
+    pub fn pool_constant(&mut self, value: &[u8]) -> StackVariable {
+        let var = self.var(1, script!{ { value.to_vec() } }, &format!("const({})", hex::encode(value)));
+        self.constants.push((value.to_vec(), var));
+        var
+    }
+
+    // pushes the constant, copying it from the pool when that is shorter than pushing it again
+    pub fn use_constant(&mut self, value: &[u8]) -> StackVariable {
+        let push = script!{ { value.to_vec() } };
+        let pooled = self.constants.iter()
+            .find(|(v, var)| v == value && self.data.stack.iter().any(|x| x.id == var.id))
+            .map(|(_, var)| *var);
+        if let Some(var) = pooled {
+            if copy_from(self.get_offset(var), 1).len() < push.len() {
+                return self.copy_var(var);
+            }
+        }
+        self.var(1, push, &format!("const({})", hex::encode(value)))
+    }
+
     pub fn hexstr_as_nibbles(&mut self, value: &str) -> StackVariable {
         let bytes = Vec::from_hex(value).unwrap();
         let total = bytes.len() * 2;
@@ -853,8 +2536,21 @@ impl StackTracker {
     }
 
 
+    // script numbers are signed, so values above i32::MAX (0x7fffffff) are pushed using 5 bytes
+    // and can not be used as operands of the arithmetic opcodes (which only accept 4 bytes).
+    // Use number_exact to push any i64 with its minimal encoding.
     pub fn number(&mut self, value: u32) -> StackVariable {
-        self.var(1, script!{{value}}, &format!("number({:#x})", value))
+        let script = if value == 0 { script!{ OP_0 } } else { script!{ { value } } };
+        self.var(1, script, &format!("number({:#x})", value))
+    }
+
+    pub fn number_exact(&mut self, value: i64) -> StackVariable {
+        let script = if (-1..=16).contains(&value) {
+            script!{ { value } }
+        } else {
+            script!{ { encode_number(value) } }
+        };
+        self.var(1, script, &format!("number({})", value))
     }
 
     pub fn repeat(&mut self, mut times: u32) -> Vec<StackVariable> {
@@ -873,20 +2569,36 @@ impl StackTracker {
 
     }
 
+    // unrolls `body` `times` times pushing the index of the iteration (named "index(i)") before each call so the body
+    // can use it (i.e. to get a value from a table). If the body doesn't consume the index it's dropped after the call
+    pub fn repeat_indexed(&mut self, times: u32, body: impl Fn(&mut StackTracker, StackVariable)) {
+        for i in 0..times {
+            let script = if i == 0 { script!{ OP_0 } } else { script!{ { i } } };
+            let index = self.var(1, script, &format!("index({})", i));
+            body(self, index);
+            if self.data.stack.last().is_some_and(|v| v.id == index.id) {
+                self.drop(index);
+            } else if self.data.stack.iter().any(|v| v.id == index.id) {
+                self.nip_var(index);
+            }
+        }
+    }
+
     pub fn numberi(&mut self, value: i32) -> StackVariable {
         self.var(1, script!{{value}}, &format!("number({:#x})", value))
     }
 
     pub fn byte(&mut self, value: u8) -> StackVariable {
-        self.var(2, byte_to_nibble(value), &format!("byte({:#x})", value))
+        self.var(self.base.elements(8), number_to_base(value as u32, 8, self.base), &format!("byte({:#x})", value))
     }
 
+    // pushes the number as 8 nibbles (4 bytes with Base::Byte) so the whole u32 range (up to 0xffffffff) fits
     pub fn number_u32(&mut self, value: u32) -> StackVariable {
-        self.var(8, number_to_nibble(value), &format!("number_u32({:#x})", value))
+        self.var(self.u32_size(), number_to_base(value, 32, self.base), &format!("number_u32({:#x})", value))
     }
 
     pub fn number_u16(&mut self, value: u16) -> StackVariable {
-        self.var(4, number_16_to_nibble(value), &format!("number_u16({:#x})", value))
+        self.var(self.base.elements(16), number_to_base(value as u32, 16, self.base), &format!("number_u16({:#x})", value))
     }
 
     pub fn number_u32_u8(&mut self, value: u32) -> StackVariable {
@@ -902,12 +2614,26 @@ impl StackTracker {
         self.op(OP_NOP, 0, true, "OP_NOP()").unwrap()
     }
 
+    // emits OP_RETURN <data> to create a data carrier.
+    // The script becomes unspendable: the execution aborts on OP_RETURN so run() will always fail.
+    pub fn op_return(&mut self, data: &[u8]) {
+        self.custom(script!{ OP_RETURN { data.to_vec() } }, 0, false, 0, "OP_RETURN");
+    }
+
+    // dropping is not a use of the variable, so it does not go through custom
     pub fn op_drop(&mut self) {
-        self.op(OP_DROP, 1, false, "OP_DROP");
+        let var = self.data.pop_stack();
+        self.consume(var);
+        self.push_script(script!{ OP_DROP }, "OP_DROP");
     }
 
     pub fn op_2drop(&mut self) {
-        self.op(OP_2DROP, 2, false, "OP_2DROP");
+        self.assert_single_elements(2, "OP_2DROP", "drop");
+        for _ in 0..2 {
+            let var = self.data.pop_stack();
+            self.consume(var);
+        }
+        self.push_script(script!{ OP_2DROP }, "OP_2DROP");
     }
 
     pub fn op_depth(&mut self) -> StackVariable {
@@ -916,21 +2642,26 @@ impl StackTracker {
 
     pub fn op_nip(&mut self)  {
         let x = self.data.pop_stack();
-        self.data.pop_stack();
+        let y = self.data.pop_stack();
+        self.consume(y);
         self.data.push_stack(x);
         self.op(OP_NIP, 0, false, "OP_NIP");
     }
 
     pub fn op_dup(&mut self) -> StackVariable {
+        self.mark_top_used(1);
         self.op(OP_DUP, 0, true, "OP_DUP").unwrap()
     }
     
     pub fn op_2dup(&mut self) -> (StackVariable, StackVariable) {
+        self.assert_single_elements(2, "OP_2DUP", "copy_var");
+        self.mark_top_used(2);
         let x = self.define(1, "OP_DUP");
         (x, self.op(OP_2DUP, 0, true, "OP_DUP").unwrap())
     }
 
     pub fn op_3dup(&mut self) -> (StackVariable, StackVariable, StackVariable) {
+        self.mark_top_used(3);
         let x = self.define(1, "OP_DUP");
         let y = self.define(1, "OP_DUP");
         (x, y, self.op(OP_3DUP, 0, true, "OP_DUP").unwrap())
@@ -938,6 +2669,9 @@ impl StackTracker {
 
 
     pub fn get_value_from_table(&mut self, table: StackVariable, offset: Option<u32> ) -> StackVariable {
+        // the offset is computed from the top of the table, so a table that lost elements would read the wrong cell
+        assert_eq!(self.get_size(table), table.size, "The table {:?} has been partially consumed and now has {} elements", table, self.get_size(table));
+        self.data.mark_used(table);
         self.number(self.get_offset(table)-1 + offset.unwrap_or(0));
         self.op_add();
         let v = self.op_pick();
@@ -945,9 +2679,71 @@ impl StackTracker {
         v
     }
 
+    // same as get_value_from_table for a table of `table_len` entries (starting at offset) adding a runtime guard
+    // that fails the script if the index on top of the stack is out of range
+    pub fn get_value_from_table_checked(&mut self, table: StackVariable, offset: Option<u32>, table_len: u32) -> StackVariable {
+        assert!(offset.unwrap_or(0) + table_len <= table.size, "The table {:?} doesn't have {} entries after offset {}", table, table_len, offset.unwrap_or(0));
+        self.mark_top_used(1);
+        self.custom(script!{ OP_DUP 0 { table_len } OP_WITHIN OP_VERIFY }, 0, false, 0, "table_index_guard");
+        self.get_value_from_table(table, offset)
+    }
+
+    // gets the entry `index` known when building the script, so an out of range index is caught here
+    // and there is no need to push and add the index
+    pub fn get_value_from_table_at(&mut self, table: StackVariable, offset: Option<u32>, table_len: u32, index: u32) -> StackVariable {
+        assert!(offset.unwrap_or(0) + table_len <= table.size, "The table {:?} doesn't have {} entries after offset {}", table, table_len, offset.unwrap_or(0));
+        assert!(index < table_len, "The index {} is out of range for the table {:?} of {} entries", index, table, table_len);
+        assert_eq!(self.get_size(table), table.size, "The table {:?} has been partially consumed and now has {} elements", table, self.get_size(table));
+        self.data.mark_used(table);
+        self.number(self.get_offset(table) + offset.unwrap_or(0) + index);
+        let v = self.op_pick();
+        self.rename(v, &format!("from:({})", self.data.names[&table.id]));
+        v
+    }
+
+    // pushes a table of constants and moves it to the altstack to keep the main stack clear.
+    // it uses the same order as get_value_from_table (the index 0 is values[0])
+    pub fn build_altstack_table(&mut self, values: &[u32]) -> StackVariable {
+        assert!(!values.is_empty(), "The table needs at least one value");
+        let script = script! {
+            for v in values.iter().rev() {
+                { *v }
+            }
+        };
+        self.var(values.len() as u32, script, "altstack_table");
+        self.to_altstack()
+    }
+
+    // gets the value at the index on top of the stack from a table created with build_altstack_table that needs to be
+    // on top of the altstack. The table is brought back to the stack to pick the value and then restored, so every
+    // lookup costs O(table size) opcodes (the moves of the table to and from the altstack and around the value)
+    pub fn get_from_altstack_table(&mut self, table: StackVariable, index: StackVariable) -> StackVariable {
+        assert_eq!(self.data.altstack.last().map(|v| v.id), Some(table.id), "The table {:?} needs to be on top of the altstack", table);
+        assert_eq!(self.get_var_from_stack(0).id, index.id, "The index {:?} needs to be on top of the stack", index);
+        self.from_altstack();
+        self.move_var(index);
+        let value = self.get_value_from_table(table, None);
+        self.move_var(table);
+        self.to_altstack();
+        value
+    }
+
+    // same as get_value_from_table but selecting the element with a tree of OP_IF instead of computing the offset.
+    // the index needs to be on top of the stack and be smaller than the size of the table
+    pub fn select_from_table_if(&mut self, table: StackVariable, index: StackVariable) -> StackVariable {
+        assert_eq!(self.get_var_from_stack(0).id, index.id, "The index {:?} needs to be on top of the stack", index);
+        let size = self.get_size(table);
+        assert!(size <= self.max_table_if_size, "The table {:?} is bigger than {}", table, self.max_table_if_size);
+        let offset = self.get_offset(table) - 1;
+        let name = format!("from:({})", self.get_var_name(table));
+        self.data.mark_used(table);
+        self.custom(select_if_tree(0, size, offset), 1, true, 0, &name).unwrap()
+    }
+
+    #[cfg(feature = "std")]
     pub fn debug(&mut self) {
         println!("Max stack size: {}", self.max_stack_size);
-        self.push_script(script!{});
+        self.push_script(script!{}, "debug");
         print_execute_step(self, self.script.len()-1);
     }
 
@@ -964,9 +2760,18 @@ mod tests {
     pub use bitcoin_script::{define_pushable, script};
     
     define_pushable!();
-    use super::{StackData, StackTracker, StackVariable};
-
-    use crate::debugger::{debug_script, show_altstack, show_stack};
+    use super::{Base, CaseBuilder, HORNER_X_BITS, LintWarning, LINT_MAX_ROLL_DEPTH, MAX_OPERAND_NAME_LEN, ScriptFailure, StackData, Stats, StackTracker, StackVariable, WitnessLayout};
+
+    use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d, Hash};
+    use bitcoin::opcodes::all::OP_CHECKMULTISIG;
+    use bitcoin::Sequence;
+    use crate::test_util::{hash160_hex, hash256_hex, ripemd160_hex, sha256_hex};
+    use hex::FromHex;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    use crate::debugger::{debug_script, dummy_pubkey, dummy_sig_tx_template, execute_step, dummy_signature, show_altstack, show_stack, ExecCtx, Options};
     use crate::script_util::*;
 
     #[test]
@@ -980,6 +2785,37 @@ mod tests {
 
     }
 
+    // reverse_u32 reverses the order of the elements, so the expected value depends on the base
+    fn base_gadget(base: Base, reversed: u32) -> StackTracker {
+        let mut stack = StackTracker::new_with_base(base);
+        let x = stack.number_u32(0x12345678);
+        let mut copy = stack.copy_var(x);
+        stack.reverse_u32(copy);
+        let mut expected = stack.number_u32(reversed);
+        stack.equals(&mut copy, true, &mut expected, true);
+        stack.number_u32(0x12345678);
+        stack.custom(script!{ {verify_n(stack.u32_size())} }, 2, false, 0, "verify");
+        stack.op_true();
+        stack
+    }
+
+    #[test]
+    fn test_base() {
+        let stack = base_gadget(Base::Nibble, 0x87654321);
+        assert_eq!(stack.u32_size(), 8);
+        assert!(stack.run().success);
+
+        let stack = base_gadget(Base::Byte, 0x78563412);
+        assert_eq!(stack.u32_size(), 4);
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new_with_base(Base::Byte);
+        let byte = stack.byte(0xfe);
+        assert_eq!(byte.size(), 1);
+        let x = stack.number_u16(0xbeaf);
+        assert_eq!(x.size(), 2);
+    }
+
     #[test]
     fn test_byte() {
         let mut stack = StackTracker::new();
@@ -993,6 +2829,53 @@ mod tests {
         assert!(stack.run().success);
     }
 
+    #[test]
+    fn test_number_exact() {
+        for value in [0, 1, 16, -1, -16, 127, 128, -128, 0x7fffffff, 0x80000000, 0xffffffff, -0x7fffffff, -0xffffffff] {
+            let mut stack = StackTracker::new();
+            stack.number_exact(value);
+            let (exec, _) = debug_script(stack.get_script());
+            let pushed = exec.stack().get(0);
+            assert_eq!(pushed, encode_number(value));
+            assert_eq!(decode_number(&pushed), Some(value));
+        }
+
+        assert_eq!(decode_number(&[0xff; 8]), Some(-0x7fffffffffffffff));
+        assert_eq!(decode_number(&[0xab; 32]), None);
+
+        let mut stack = StackTracker::new();
+        stack.number_exact(0x7fffffff);
+        stack.op_1sub();
+        stack.number_exact(0x7ffffffe);
+        stack.op_equal();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_op_add_checked() {
+        let mut stack = StackTracker::new();
+        stack.number_exact(0x7ffffffe);
+        stack.number(1);
+        stack.op_add_checked();
+        stack.number_exact(0x7fffffff);
+        stack.op_equal();
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new();
+        stack.number_exact(0x7fffffff);
+        stack.number(1);
+        stack.op_add_checked();
+        let ret = stack.run();
+        assert!(ret.error);
+        assert!(ret.error_msg.contains("Verify"));
+
+        let mut stack = StackTracker::new();
+        stack.number_exact(-0x7fffffff);
+        stack.number(1);
+        stack.op_sub_checked();
+        assert!(stack.run().error);
+    }
+
     #[test]
     fn test_repeat() {
         let mut stack = StackTracker::new();
@@ -1035,6 +2918,23 @@ mod tests {
         assert!(stack.run().success);
     }
 
+    #[test]
+    fn test_nip_var() {
+        let mut stack = StackTracker::new();
+        let mut x = stack.number_u32(0x1234);
+        let y = stack.number_u32(0x5678);
+        let mut z = stack.number(3);
+        stack.nip_var(y);
+        assert_eq!(stack.data.stack, vec![x, z]);
+
+        let mut zz = stack.number(3);
+        stack.equals(&mut z, true, &mut zz, true);
+        let mut xx = stack.number_u32(0x1234);
+        stack.equals(&mut x, true, &mut xx, true);
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
     #[test]
     fn test_copy_var() {
         let mut stack = StackTracker::new();
@@ -1053,201 +2953,813 @@ mod tests {
     }
 
     #[test]
-    fn test_define_var() {
+    fn test_copy_altstack_var() {
         let mut stack = StackTracker::new();
-        let pre_existent = stack.define(8, "pre_existent");
-        let _ = stack.number_u32(4444);
-        let _ = stack.move_var(pre_existent);
-
-        let script = script! {
-            { number_to_nibble(1234) }
-            { stack.get_script()}
-            { number_to_nibble(1234) }
-            { verify_n(8) }
-            { drop_count(8) }
-            OP_TRUE
-        };
+        let x = stack.number_u32(0x1234);
+        stack.to_altstack();
+        let y = stack.number(5);
+        stack.to_altstack();
 
-        let (ret,_) = debug_script(script);
-        assert!(ret.result().unwrap().success);
-    }
+        let mut copy = stack.copy_var(x);
+        assert_eq!(stack.data.stack, vec![copy]);
+        assert_eq!(stack.data.altstack, vec![x, y]);
 
+        let mut expected = stack.number_u32(0x1234);
+        stack.equals(&mut copy, true, &mut expected, true);
 
-    #[test]
-    fn test_clear_definitions() {
-        let mut stack = StackTracker::new();
-        stack.number(1);
-        stack.repeat(7);
-        stack.debug();
-        stack.clear_definitions();
-        let mut x = stack.define(8, "number");
-        stack.debug();
-        let mut y = stack.number_u32(0x1111_1111);
-        stack.equals(&mut x, true, &mut y, true);
+        stack.from_altstack();
+        stack.number(5);
+        stack.op_equalverify();
+        stack.from_altstack();
+        stack.drop(x);
         stack.op_true();
         assert!(stack.run().success);
     }
 
     #[test]
-    fn test_copy_var_sub_n() {
+    fn test_use_var() {
         let mut stack = StackTracker::new();
-        let x = stack.number_u32(0xdeadbeaf);
-        let _ = stack.copy_var_sub_n(x, 0);
-        let _ = stack.copy_var_sub_n(x, 7);
+        let x = stack.number(3);
+        let y = stack.number(4);
 
-        let script = script! {
-            { stack.get_script()}
-            OP_15
-            { verify_n(1) }
-            OP_13
-            { verify_n(1) }
-            { drop_count(8) }
-            OP_TRUE
-        };
+        let copy = stack.use_var(x, false);
+        assert_ne!(copy, x);
+        assert_eq!(stack.data.stack, vec![x, y, copy]);
 
-        let (ret,_) = debug_script(script);
-        assert!(ret.result().unwrap().success);
+        let moved = stack.use_var(x, true);
+        assert_eq!(moved, x);
+        assert_eq!(stack.data.stack, vec![y, copy, x]);
+
+        stack.op_equalverify();
+        stack.drop(y);
+        stack.op_true();
+        assert!(stack.run().success);
     }
 
     #[test]
-    fn test_move_sub_n() {
+    fn test_dedupe_names() {
         let mut stack = StackTracker::new();
-        let mut x = stack.number_u32(0xdeadbeaf);
-        let _ = stack.move_var_sub_n(&mut x, 1);
-        let _ = stack.move_var_sub_n(&mut x, 1);
+        stack.number(1);
+        stack.number(2);
+        let a = stack.op_add();
+        stack.rename(a, "result");
+        stack.number(3);
+        let b = stack.op_1add();
+        stack.rename(b, "result");
+        let c = stack.number(4);
+        stack.rename(c, "result");
+        stack.to_altstack();
 
-        let script = script! {
-            { stack.get_script()}
-            OP_10
-            { verify_n(1) }
-            OP_14
-            { verify_n(1) }
-            { drop_count(6) }
-            OP_TRUE
-        };
+        stack.dedupe_names();
+        assert_eq!(stack.get_var_name(a), "result");
+        assert_eq!(stack.get_var_name(b), "result#2");
+        assert_eq!(stack.get_var_name(c), "result#3");
+    }
 
-        let (ret,_) = debug_script(script);
-        assert!(ret.result().unwrap().success);
+    #[test]
+    fn test_estimated_witness_size() {
+        let mut layout = WitnessLayout::new();
+        layout.push_bytes("a", vec![7; 32]);
+        layout.push_bytes("b", vec![7; 32]);
+        let mut stack = StackTracker::from_witness(&layout);
+        stack.op_equal();
+        let script_len = stack.get_script().len();
+        assert_eq!(script_len, 1);
+        // count + 2 inputs + script + control block
+        assert_eq!(stack.estimated_witness_size(), 1 + 2 * (1 + 32) + (1 + script_len) + (1 + 33));
+        assert!(stack.run().success);
     }
 
     #[test]
-    fn test_equals() {
+    fn test_sig_placeholder() {
+        let pubkeys = (1..=3).map(|i| vec![i; 32]).collect::<Vec<_>>();
+        let mut stack = StackTracker::new();
+        // signatures of the pubkeys 0 and 2, the one of pubkeys[0] on top
+        stack.sig_placeholder(64);
+        stack.sig_placeholder(0);
+        stack.sig_placeholder(64);
+        stack.checksigadd_threshold(&pubkeys, 2);
+        let script_len = stack.get_script().len();
+        assert_eq!(script_len, 1 + 3 * (1 + 32 + 1) + 2);
+        // count + 3 signatures + script + control block
+        assert_eq!(stack.estimated_witness_size(), 1 + (1 + 64) + 1 + (1 + 64) + (1 + script_len) + (1 + 33));
+    }
 
-        //test without moving the last number
+    #[test]
+    #[should_panic(expected = "before emitting any script")]
+    fn test_sig_placeholder_after_script() {
         let mut stack = StackTracker::new();
-        let mut x = stack.number_u32(0x123456);
-        let mut y = stack.copy_var(x);
-        stack.equals(&mut x, true, &mut y, true);
+        stack.number(1);
+        stack.sig_placeholder(64);
+    }
+
+    #[test]
+    fn test_from_witness() {
+        let preimage = vec![1u8, 2, 3, 4];
+        let digest = sha256::Hash::hash(&preimage).to_byte_array();
+
+        let mut layout = WitnessLayout::new();
+        layout.push_bytes("preimage", preimage).push_number("amount", 1000);
+        assert_eq!(layout.witness().len(), 2);
+
+        let mut stack = StackTracker::from_witness(&layout);
+        let amount = stack.get_var_from_stack(0);
+        assert_eq!(stack.get_var_name(amount), "amount");
+        stack.number_exact(1000);
+        stack.op_equalverify();
+        stack.assert_sha256_preimage(&digest);
         stack.op_true();
         assert!(stack.run().success);
 
-        let mut stack = StackTracker::new();
-        let mut x = stack.number_u32(0x123456);
-        let mut y = stack.copy_var(x);
+        let mut layout = WitnessLayout::new();
+        layout.push_bytes("preimage", vec![1, 2, 3]).push_number("amount", 1000);
+        let mut stack = StackTracker::from_witness(&layout);
+        stack.number_exact(1000);
+        stack.op_equalverify();
+        stack.assert_sha256_preimage(&digest);
         stack.op_true();
-        stack.equals(&mut x, true, &mut y, true);
-        assert!(stack.run().success);
+        assert!(!stack.run().success);
+    }
 
+    #[test]
+    fn test_observer() {
+        let ops = Rc::new(RefCell::new(Vec::new()));
         let mut stack = StackTracker::new();
-        let mut x = stack.number_u32(0x123456);
-        let mut y = stack.copy_var(x);
-        stack.equals(&mut x, false, &mut y, false);
-        stack.drop(y);
-        stack.drop(x);
-        stack.op_true();
-        assert!(stack.run().success);
+        let recorder = ops.clone();
+        stack.set_observer(Box::new(move |name, data| {
+            recorder.borrow_mut().push((name.to_string(), data.stack().len()));
+        }));
+
+        stack.number(1);
+        let x = stack.number(2);
+        stack.rename(x, "x");
+        stack.op_add();
+        stack.to_altstack();
 
+        let cloned = stack.clone();
+        stack.from_altstack();
+        stack.clear_observer();
+        stack.drop(stack.get_var_from_stack(0));
 
+        let expected = [("number(0x1)", 1), ("number(0x2)", 2), ("rename(x)", 2), ("OP_ADD()", 1), ("to_altstack", 0), ("from_altstack", 1)];
+        assert_eq!(*ops.borrow(), expected.iter().map(|(n, d)| (n.to_string(), *d)).collect::<Vec<_>>());
+        assert!(format!("{:?}", cloned).contains("observer: None"));
     }
 
+    #[test]
+    fn test_current_depth() {
+        let mut stack = StackTracker::new();
+        assert_eq!(stack.current_depth(), 0);
+        assert_eq!(stack.current_var_count(), 0);
+
+        stack.number(1);
+        stack.number_u32(0x12345678);
+        stack.number(2);
+        stack.to_altstack();
+        assert_eq!(stack.current_depth(), 1 + stack.u32_size());
+        assert_eq!(stack.current_var_count(), 2);
+
+        stack.op_depth();
+        assert_eq!(stack.current_depth(), 2 + stack.u32_size());
+        assert_eq!(stack.current_var_count(), 3);
+    }
 
     #[test]
-    fn test_join() {
+    #[should_panic(expected = "OP_ADD() expects a number")]
+    fn test_op_add_multi_element() {
         let mut stack = StackTracker::new();
-        let mut x = stack.number_u32(0xdeadbeaf);
-        let _y = stack.number_u32(0x12345678);
-        stack.join(&mut x);
-        let _  = stack.number_u32(0x00000000);
-        
-        stack.move_var(x);
+        stack.number(1);
+        stack.number_u32(2);
+        stack.op_add();
+    }
 
+    #[test]
+    #[should_panic(expected = "OP_WITHIN() expects a number")]
+    fn test_op_within_multi_element() {
+        let mut stack = StackTracker::new();
+        stack.number_u32(2);
+        stack.number(1);
+        stack.number(3);
+        stack.op_within();
+    }
 
-        let script = script! {
-            { stack.get_script()}
-            { number_to_nibble(0xdeadbeaf) }
-            { number_to_nibble(0x12345678) }
-            { verify_n(16) }
-            { drop_count(8) }
-            OP_TRUE
-        };
+    fn multi_element_gadget(count: u32, multi_depth: u32) -> StackTracker {
+        let mut stack = StackTracker::new();
+        for i in (0..count).rev() {
+            if i == multi_depth {
+                stack.number_u32(i);
+            } else {
+                stack.number(i);
+            }
+        }
+        stack
+    }
 
-        let (ret,_) = debug_script(script);
-        assert!(ret.result().unwrap().success);
+    #[test]
+    #[should_panic(expected = "OP_2SWAP requires elements of size 1")]
+    fn test_op_2swap_multi_element() {
+        multi_element_gadget(4, 3).op_2swap();
     }
 
+    #[test]
+    #[should_panic(expected = "OP_2ROT requires elements of size 1")]
+    fn test_op_2rot_multi_element() {
+        multi_element_gadget(6, 5).op_2rot();
+    }
 
     #[test]
-    fn test_join_in_stack() {
-        let mut stack = StackTracker::new();
-        let x = stack.number_u32(0xdeadbeaf);
-        stack.explode(x);
-        let mut a = stack.join_in_stack(7, 4, Some("first-half"));
-        let mut b = stack.join_in_stack(3, 4, Some("second-half"));
+    #[should_panic(expected = "OP_2OVER requires elements of size 1")]
+    fn test_op_2over_multi_element() {
+        multi_element_gadget(4, 2).op_2over();
+    }
 
-        let mut bb = stack.number_u16(0xbeaf);
-        stack.equals(&mut b, true, &mut bb, true);
+    #[test]
+    #[should_panic(expected = "OP_2DUP requires elements of size 1")]
+    fn test_op_2dup_multi_element() {
+        multi_element_gadget(2, 0).op_2dup();
+    }
 
-        let mut aa = stack.number_u16(0xdead);
-        stack.equals(&mut a, true, &mut aa, true);
+    #[test]
+    #[should_panic(expected = "OP_2DROP requires elements of size 1")]
+    fn test_op_2drop_multi_element() {
+        multi_element_gadget(2, 1).op_2drop();
+    }
 
+    fn constant_gadget(pooled: bool) -> StackTracker {
+        let hash = vec![0xab; 32];
+        let mut stack = StackTracker::new();
+        let constant = if pooled { Some(stack.pool_constant(&hash)) } else { None };
+        for _ in 0..5 {
+            stack.use_constant(&hash);
+            stack.use_constant(&hash);
+            stack.op_equalverify();
+        }
+        if let Some(constant) = constant {
+            stack.drop(constant);
+        }
         stack.op_true();
-        assert!(stack.run().success);
+        stack
     }
 
     #[test]
-    fn test_explode_and_join_in_stack() {
+    fn test_use_constant() {
+        let plain = constant_gadget(false);
+        let pooled = constant_gadget(true);
+        assert!(plain.run().success);
+        assert!(pooled.run().success);
+        assert!(pooled.get_script().len() < plain.get_script().len());
+
+        // OP_0 is shorter than copying it
         let mut stack = StackTracker::new();
-        let x = stack.number_u32(0xdeadbeaf);
-        let y = stack.number_u32(0x0);
-        stack.explode(x);
-        stack.debug();
-        let mut a = stack.join_in_stack(7 + 8, 4, Some("first-half"));
-        stack.debug();
-        let mut b = stack.join_in_stack(3 + 8, 4, Some("second-half"));
-        stack.debug();
-
-        stack.drop(y);
+        stack.pool_constant(&[]);
+        let before = stack.get_script().len();
+        stack.use_constant(&[]);
+        assert_eq!(stack.get_script().len(), before + 1);
+    }
 
-        let mut bb = stack.number_u16(0xbeaf);
-        stack.equals(&mut b, true, &mut bb, true);
+    #[test]
+    fn test_try_number_to_nibble() {
+        assert_eq!(try_number_to_nibble(0x12345678, 8).unwrap(), number_to_nibble(0x12345678));
+        assert_eq!(try_number_to_nibble(0x1234, 4).unwrap(), number_16_to_nibble(0x1234));
+        assert_eq!(try_number_to_nibble(0xf, 1).unwrap(), script!{ 15 });
+        assert_eq!(try_number_to_nibble(u64::MAX, 16).unwrap().instructions().count(), 16);
+        assert_eq!(try_number_to_nibble(1, 17).unwrap().instructions().count(), 17);
+        assert_eq!(try_number_to_nibble(0, 0).unwrap().len(), 0);
 
-        let mut aa = stack.number_u16(0xdead);
-        stack.equals(&mut a, true, &mut aa, true);
+        assert_eq!(try_number_to_nibble(0x12345, 4), Err(NibbleOverflow { value: 0x12345, width: 4 }));
+        assert_eq!(try_number_to_nibble(0x1_0000_0000, 8).unwrap_err().to_string(), "The value 0x100000000 does not fit in 8 nibbles");
+        assert!(try_number_to_nibble(1, 0).is_err());
+    }
 
+    #[test]
+    fn test_try_build() {
+        const MAX_STACK: u32 = 10;
+        let mut stack = StackTracker::new();
+        stack.number_u32(0x12345678);
+        stack.set_breakpoint("start");
+        let before = stack.clone();
+
+        let kept = stack.try_build(|stack| {
+            stack.comment("too big");
+            stack.number_u32(0x1234);
+            stack.get_max_stack_size() <= MAX_STACK
+        });
+        assert!(!kept);
+        assert_eq!(stack.get_script(), before.get_script());
+        assert_eq!(format!("{:?}", stack), format!("{:?}", before));
+
+        let kept = stack.try_build(|stack| {
+            stack.number(1);
+            stack.get_max_stack_size() <= MAX_STACK
+        });
+        assert!(kept);
+        assert_eq!(stack.current_depth(), 9);
+
+        let checkpoint = stack.checkpoint();
+        let top = stack.get_var_from_stack(0);
+        stack.drop(top);
+        stack.explode(stack.get_var_from_stack(0));
+        stack.rollback(checkpoint);
+        stack.drop(top);
+        let mut x = stack.get_var_from_stack(0);
+        let mut expected = stack.number_u32(0x12345678);
+        stack.equals(&mut x, true, &mut expected, true);
         stack.op_true();
         assert!(stack.run().success);
     }
 
     #[test]
-    fn test_explode() {
+    fn test_roll_depth_histogram() {
         let mut stack = StackTracker::new();
-        let x = stack.number_u32(0xdeadbeaf);
-        let orginal_size = stack.get_max_stack_size();
-        let x_parts = stack.explode(x);
-        let new_size = stack.get_max_stack_size();
-        assert_eq!(orginal_size, new_size);
-        stack.debug();
-        let temp = stack.copy_var(x_parts[1]);
+        let a = stack.number(1);
+        let b = stack.number_u32(2);
+        stack.number(3);
+        stack.move_var(a);
+        stack.move_var(b);
+        stack.number(4);
+        stack.number(5);
+        stack.number(6);
+        stack.permute_top(&[2, 0, 1]);
+        stack.op_swap();
+        stack.op_depth();
+        stack.custom(script!{ OP_ROLL }, 1, true, 0, "runtime roll");
+
+        let histogram = stack.roll_depth_histogram();
+        let expected = [(1, 1), (2, 1), (9, 9)].into_iter().collect::<BTreeMap<u32, usize>>();
+        assert_eq!(histogram, expected);
+        assert!(StackTracker::new().roll_depth_histogram().is_empty());
+
+        // a hash push is not a depth
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.hexstr(&"ab".repeat(32));
+        stack.op_swap();
+        let expected = [(1, 1)].into_iter().collect::<BTreeMap<u32, usize>>();
+        assert_eq!(stack.roll_depth_histogram(), expected);
+    }
+
+    #[test]
+    fn test_lint() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.number(2);
+        stack.op_add();
+        stack.number(3);
+        stack.op_equal();
+        assert!(stack.lint().is_empty());
+
+        // unused variables, dropped without being read
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        let b = stack.number(2);
+        stack.drop(b);
+        let c = stack.number(3);
+        stack.op_drop();
+        stack.copy_var(a);
+        stack.op_drop();
+        assert_eq!(stack.lint(), vec![
+            LintWarning::UnusedVariable { var: b, name: "number(0x2)".to_string() },
+            LintWarning::UnusedVariable { var: c, name: "number(0x3)".to_string() },
+            LintWarning::UnusedVariable { var: StackVariable::new(c.id() + 1, 1), name: "copy(number(0x1))".to_string() },
+        ]);
+
+        // leftover altstack
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        let x = stack.to_altstack();
+        stack.op_true();
+        assert_eq!(stack.lint(), vec![LintWarning::LeftoverAltstack { var: x, name: "number(0x1)".to_string() }]);
+
+        // deep roll
+        let mut stack = StackTracker::new();
+        let bottom = stack.number(1);
+        for _ in 0..LINT_MAX_ROLL_DEPTH + 1 {
+            stack.number(2);
+        }
+        stack.move_var(bottom);
+        let step = stack.get_script_len() - 1;
+        assert_eq!(stack.lint(), vec![LintWarning::DeepRoll { step, label: "move_var".to_string(), depth: LINT_MAX_ROLL_DEPTH + 1 }]);
+
+        // custom script that consumes more than declared
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.number(2);
+        stack.number(3);
+        stack.custom(script!{ OP_DROP OP_DROP }, 1, false, 0, "bad_consumes");
+        assert_eq!(stack.lint(), vec![LintWarning::ModelMismatch { step: 3, label: "bad_consumes".to_string(), expected: (2, 0), actual: (1, 0) }]);
+
+        // a hash push is not taken as a roll depth
+        let mut stack = StackTracker::new();
+        let hash = stack.hexstr(&"ab".repeat(32));
+        stack.number(1);
+        stack.move_var(hash);
+        stack.hexstr(&"ab".repeat(32));
+        stack.op_equal();
+        assert!(stack.lint().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "(number(0x1)) was already consumed at step 3")]
+    fn test_move_consumed() {
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        stack.number(2);
+        stack.move_var(a);
+        stack.op_add();
+        stack.move_var(a);
+    }
+
+    #[test]
+    fn test_consumed_at() {
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        let b = stack.number(2);
+        stack.rename(b, "b");
+        stack.drop(b);
+        assert_eq!(stack.data.consumed_at(b), Some((3, "b".to_string())));
+        assert_eq!(stack.data.consumed_at(a), None);
+
+        // a rollback forgets the variables consumed after the checkpoint
+        let checkpoint = stack.checkpoint();
+        stack.drop(a);
+        assert!(stack.data.consumed_at(a).is_some());
+        stack.rollback(checkpoint);
+        assert_eq!(stack.data.consumed_at(a), None);
+        assert!(stack.data.consumed_at(b).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "(number(0x1)) is on the altstack, use from_altstack first")]
+    fn test_move_altstack_var() {
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        stack.stash();
+        stack.number(2);
+        stack.move_var(a);
+    }
+
+    #[test]
+    #[should_panic(expected = "(number(0x2)) was already consumed at step 2")]
+    fn test_drop_consumed() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        let b = stack.number(2);
+        stack.drop(b);
+        stack.drop(b);
+    }
+
+    #[test]
+    fn test_horner_eval() {
+        let mut stack = StackTracker::new();
+        let x = stack.number(4);
+        let result = stack.horner_eval(&[3, 2, 1], x);
+        assert_eq!(stack.data.stack, vec![result]);
+        assert!(stack.lint().is_empty());
+        stack.number(57);
+        stack.op_equal();
+        assert!(stack.run().success);
+
+        // negative coefficients and x = 0
+        let mut stack = StackTracker::new();
+        let bottom = stack.number(7);
+        let x = stack.number(0);
+        stack.horner_eval(&[-5, 0, -3], x);
+        stack.numberi(-3);
+        stack.op_equalverify();
+        stack.drop(bottom);
+        stack.op_true();
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new();
+        let x = stack.number_exact(1 << HORNER_X_BITS);
+        stack.horner_eval(&[1, 0], x);
+        stack.op_drop();
+        stack.op_true();
+        assert!(stack.run().error);
+    }
+
+    #[test]
+    fn test_assert_tapscript_valid() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.number(2);
+        stack.op_add();
+        stack.number(3);
+        stack.op_equal();
+        assert_eq!(stack.assert_tapscript_valid(), Ok(()));
+
+        stack.op_verify();
+        stack.custom(script!{ OP_0 OP_0 OP_0 OP_CHECKMULTISIG }, 0, true, 0, "multisig");
+        stack.custom(script!{ OP_0 OP_0 OP_0 OP_CHECKMULTISIG }, 1, true, 0, "multisig");
+        assert_eq!(stack.assert_tapscript_valid(), Err(vec![OP_CHECKMULTISIG]));
+    }
+
+    #[test]
+    fn test_optimize_steps() {
+        let mut stack = StackTracker::new();
+        stack.number(3);
+        let output = (0..6).map(|i| (1, format!("zero[{}]", i))).collect();
+        stack.custom_ex(script!{ OP_0 OP_0 OP_0 OP_0 OP_0 OP_0 }, 0, output, 0);
+        stack.custom(script!{ OP_2DROP OP_2DROP OP_2DROP OP_TOALTSTACK OP_FROMALTSTACK }, 6, false, 0, "drops");
+        stack.hexstr("03");
+        stack.op_equal();
+
+        let steps = stack.get_script_len();
+        let size = stack.get_script().len();
+        stack.optimize_steps();
+        assert_eq!(stack.get_script_len(), steps);
+        assert!(stack.get_script().len() < size);
+        assert_eq!(stack.script[1], script!{ OP_0 OP_DUP OP_2DUP OP_2DUP });
+        assert_eq!(stack.script[2], script!{ OP_2DROP OP_2DROP OP_2DROP });
+        assert!(stack.run().success);
+        for step in 0..steps {
+            assert!(!execute_step(&stack, step).error);
+        }
+    }
+
+    #[test]
+    fn test_to_btcdeb_script() {
+        let mut stack = StackTracker::new();
+        stack.number(0);
+        stack.number(5);
+        stack.op_add();
+        stack.hexstr("abcdef");
+        stack.op_drop();
+        stack.number(1000);
+        stack.custom(script!{ OP_CSV OP_DROP -1 }, 0, true, 0, "csv");
+        assert_eq!(stack.to_btcdeb_script(), "'[OP_0 OP_5 OP_ADD 0xabcdef OP_DROP 0xe803 OP_CHECKSEQUENCEVERIFY OP_DROP OP_1NEGATE]'");
+
+        let mut layout = WitnessLayout::new();
+        layout.push_number("x", 3).push_bytes("empty", vec![]);
+        let mut stack = StackTracker::from_witness(&layout);
+        stack.op_drop();
+        stack.op_1add();
+        assert_eq!(stack.to_btcdeb_script(), "'[OP_DROP OP_1ADD]' 0x03 ''");
+    }
+
+    #[test]
+    fn test_number_zero() {
+        let mut stack = StackTracker::new();
+        stack.number(0);
+        assert_eq!(stack.get_script(), script!{ OP_0 });
+        assert_eq!(stack.get_script().as_bytes(), &[0x00]);
+        stack.op_not();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_stash_copy() {
+        let mut stack = StackTracker::new();
+        let x = stack.number(7);
+        let copy = stack.stash_copy();
+        assert_eq!(stack.data.stack, vec![x]);
+        assert_eq!(stack.data.altstack, vec![copy]);
+        assert_eq!(stack.get_var_name(copy), "copy(number(0x7))");
+
+        let mut y = stack.number_u32(0x12345678);
+        let y_copy = stack.stash_copy();
+        assert_eq!(y_copy.size, y.size);
+        stack.number(1);
+        stack.stash();
+
+        // work with the originals
+        let mut expected = stack.number_u32(0x12345678);
+        stack.equals(&mut y, true, &mut expected, true);
+        stack.op_1add();
+
+        stack.from_altstack();
+        stack.op_add();
+        let mut y_copy = stack.from_altstack();
+        let mut expected = stack.number_u32(0x12345678);
+        stack.equals(&mut y_copy, true, &mut expected, true);
+        stack.from_altstack();
+        stack.op_add();
+        stack.number(16);
+        stack.op_equal();
+        assert!(stack.data.altstack.is_empty());
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_try_get_var() {
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        let b = stack.number_u32(2);
+        let c = stack.number(3);
+
+        let mut depth = 0;
+        let mut found = Vec::new();
+        while let Some(var) = stack.try_get_var(depth) {
+            found.push(var);
+            depth += var.size;
+        }
+        assert_eq!(found, vec![c, b, a]);
+        assert_eq!(depth, stack.current_depth());
+        assert_eq!(stack.try_get_var(2), None);
+
+        let found = (0..).map_while(|i| stack.try_get_var_from_stack(i)).collect::<Vec<StackVariable>>();
+        assert_eq!(found, vec![c, b, a]);
+        assert_eq!(stack.try_get_var_from_stack(3), None);
+        assert_eq!(StackTracker::new().try_get_var_from_stack(0), None);
+    }
+
+    fn equals_order_gadget(ordered: bool) -> StackTracker {
+        let mut stack = StackTracker::new();
+        let mut y = stack.number_u32(0x12345678);
+        let mut x = stack.number_u32(0x12345678);
+        if ordered {
+            stack.equals_ordered(&mut x, true, &mut y, true);
+        } else {
+            stack.equals(&mut x, true, &mut y, true);
+        }
+        assert!(stack.data.stack.is_empty());
+        stack.op_true();
+        stack
+    }
+
+    #[test]
+    fn test_equals_order() {
+        // x is on top but it is the first operand, so only the swapped order can avoid moving it
+        let previous = equals_order_gadget(true);
+        let current = equals_order_gadget(false);
+        assert!(previous.run().success);
+        assert!(current.run().success);
+        assert!(current.get_script().len() < previous.get_script().len());
+
+        // same cost in both orders: the given order is kept
+        let build = |ordered: bool| {
+            let mut stack = StackTracker::new();
+            let mut x = stack.number_u32(0x12345678);
+            stack.number(1);
+            let mut y = stack.number_u32(0x12345678);
+            stack.number(2);
+            if ordered {
+                stack.equals_ordered(&mut x, false, &mut y, false);
+            } else {
+                stack.equals(&mut x, false, &mut y, false);
+            }
+            stack.get_script()
+        };
+        assert_eq!(build(true), build(false));
+
+        // the observer only sees the chosen order
+        let count = Rc::new(RefCell::new(0));
+        let counter = count.clone();
+        let mut stack = StackTracker::new();
+        let mut y = stack.number(1);
+        let mut x = stack.number(1);
+        stack.set_observer(Box::new(move |_, _| *counter.borrow_mut() += 1));
+        stack.equals(&mut x, true, &mut y, true);
+        assert_eq!(*count.borrow(), stack.get_script_len() - 2);
+    }
+
+    #[test]
+    #[cfg(feature = "op_cat")]
+    fn test_merkle_step() {
+        let leaves = (0..4u8).map(|i| sha256::Hash::hash(&[i]).to_byte_array().to_vec()).collect::<Vec<Vec<u8>>>();
+        let pair = |l: &[u8], r: &[u8]| sha256::Hash::hash(&[l, r].concat()).to_byte_array().to_vec();
+        let h01 = pair(&leaves[0], &leaves[1]);
+        let h23 = pair(&leaves[2], &leaves[3]);
+        let root = pair(&h01, &h23);
+
+        // proof for the leaf 2: the leaf 3 on the right and then h01 on the left
+        let mut stack = StackTracker::new();
+        let sibling_1 = stack.hexstr(&hex::encode(&h01));
+        let sibling_0 = stack.hexstr(&hex::encode(&leaves[3]));
+        stack.hexstr(&hex::encode(&leaves[2]));
+        stack.merkle_step(sibling_0, false);
+        let node = stack.merkle_step(sibling_1, true);
+        assert_eq!(stack.data.stack, vec![node]);
+        assert!(stack.assert_tapscript_valid().is_err());
+        stack.hexstr(&hex::encode(&root));
+        stack.op_equal();
+        assert!(stack.run().success);
+
+        // the same proof with the wrong side fails
+        let mut stack = StackTracker::new();
+        let sibling_1 = stack.hexstr(&hex::encode(&h01));
+        let sibling_0 = stack.hexstr(&hex::encode(&leaves[3]));
+        stack.hexstr(&hex::encode(&leaves[2]));
+        stack.merkle_step(sibling_0, true);
+        stack.merkle_step(sibling_1, true);
+        stack.hexstr(&hex::encode(&root));
+        stack.op_equal();
+        assert!(!stack.run().success);
+    }
+
+    #[test]
+    fn test_to_altstack_same_order() {
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        let b = stack.number_u32(2);
+        let c = stack.number(3);
+        stack.to_altstack_count(3);
+        assert_eq!(stack.data.altstack, vec![c, b, a]);
+        stack.from_altstack_count(3);
+        assert_eq!(stack.data.stack, vec![a, b, c]);
+
+        stack.to_altstack_same_order(3);
+        assert_eq!(stack.data.altstack, vec![a, b, c]);
+        // each variable is stored with its elements reversed as usual
+        assert_eq!(stack.final_altstack(), vec![vec![1], vec![2], vec![], vec![], vec![], vec![], vec![], vec![], vec![], vec![3]]);
+
+        stack.from_altstack_count(3);
+        assert_eq!(stack.data.stack, vec![c, b, a]);
+        stack.number(1);
+        stack.op_equalverify();
+        stack.drop(b);
+        stack.number(3);
+        stack.op_equal();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_final_altstack() {
+        let mut stack = StackTracker::new();
+        stack.number(5);
+        stack.to_altstack();
+        stack.hexstr("abcdef");
+        stack.to_altstack();
+        stack.op_true();
+        assert!(stack.run().success);
+        assert_eq!(stack.final_altstack(), vec![vec![5], vec![0xab, 0xcd, 0xef]]);
+
+        let mut stack = StackTracker::new();
+        stack.op_true();
+        assert!(stack.final_altstack().is_empty());
+    }
+
+    #[test]
+    fn test_define_var() {
+        let mut stack = StackTracker::new();
+        let pre_existent = stack.define(8, "pre_existent");
+        let _ = stack.number_u32(4444);
+        let _ = stack.move_var(pre_existent);
+
+        let script = script! {
+            { number_to_nibble(1234) }
+            { stack.get_script()}
+            { number_to_nibble(1234) }
+            { verify_n(8) }
+            { drop_count(8) }
+            OP_TRUE
+        };
+
+        let (ret,_) = debug_script(script);
+        assert!(ret.result().unwrap().success);
+    }
+
+
+    #[test]
+    fn test_clear_definitions() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.repeat(7);
         stack.debug();
-        stack.drop(temp);
+        stack.clear_definitions();
+        let mut x = stack.define(8, "number");
+        stack.debug();
+        let mut y = stack.number_u32(0x1111_1111);
+        stack.equals(&mut x, true, &mut y, true);
+        stack.op_true();
+        assert!(stack.run().success);
+    }
 
-        stack.move_var(x_parts[2]);
+    #[test]
+    fn test_copy_var_sub_n() {
+        let mut stack = StackTracker::new();
+        let x = stack.number_u32(0xdeadbeaf);
+        let _ = stack.copy_var_sub_n(x, 0);
+        let _ = stack.copy_var_sub_n(x, 7);
+
+        let script = script! {
+            { stack.get_script()}
+            OP_15
+            { verify_n(1) }
+            OP_13
+            { verify_n(1) }
+            { drop_count(8) }
+            OP_TRUE
+        };
+
+        let (ret,_) = debug_script(script);
+        assert!(ret.result().unwrap().success);
+    }
+
+    #[test]
+    fn test_move_sub_n() {
+        let mut stack = StackTracker::new();
+        let mut x = stack.number_u32(0xdeadbeaf);
+        let _ = stack.move_var_sub_n(&mut x, 1);
+        let _ = stack.move_var_sub_n(&mut x, 1);
 
         let script = script! {
             { stack.get_script()}
             OP_10
             { verify_n(1) }
-            { drop_count(7) }
+            OP_14
+            { verify_n(1) }
+            { drop_count(6) }
             OP_TRUE
         };
 
@@ -1256,39 +3768,1247 @@ mod tests {
     }
 
     #[test]
-    fn test_get_from_table() {
-        //one element table
+    fn test_equals() {
+
+        //test without moving the last number
         let mut stack = StackTracker::new();
-        let x = stack.number(123);
-        stack.number(0);
-        stack.get_value_from_table(x, None);
-        stack.number(123);
-        stack.op_equalverify();
-        stack.drop(x);
+        let mut x = stack.number_u32(0x123456);
+        let mut y = stack.copy_var(x);
+        stack.equals(&mut x, true, &mut y, true);
+        stack.op_true();
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new();
+        let mut x = stack.number_u32(0x123456);
+        let mut y = stack.copy_var(x);
+        stack.op_true();
+        stack.equals(&mut x, true, &mut y, true);
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new();
+        let mut x = stack.number_u32(0x123456);
+        let mut y = stack.copy_var(x);
+        stack.equals(&mut x, false, &mut y, false);
+        stack.drop(y);
+        stack.drop(x);
+        stack.op_true();
+        assert!(stack.run().success);
+
+
+    }
+
+
+    #[test]
+    fn test_equalverify_from_altstack() {
+        let mut stack = StackTracker::new();
+        stack.number_u32(0x12345678);
+        stack.to_altstack();
+        let x = stack.number_u32(0x87654321);
+        stack.reverse_u32(x);
+        stack.equalverify_from_altstack(8);
+        assert!(stack.data.stack.is_empty());
+        assert!(stack.data.altstack.is_empty());
+        stack.op_true();
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new();
+        stack.number_u32(0x12345678);
+        stack.to_altstack();
+        stack.number_u32(0x12345679);
+        stack.equalverify_from_altstack(8);
+        stack.op_true();
+        assert!(!stack.run().success);
+
+        // the names of the compared variables are removed
+        let mut stack = StackTracker::new();
+        let expected = stack.number_u32(0x12345678);
+        stack.to_altstack();
+        let x = stack.number_u32(0x12345678);
+        stack.equalverify_from_altstack(8);
+        assert_eq!(stack.data.get_name(expected), None);
+        assert_eq!(stack.data.get_name(x), None);
+        assert!(stack.data.consumed_at(x).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "The top 3 elements of the stack are not aligned with the variables")]
+    fn test_equalverify_from_altstack_not_aligned() {
+        let mut stack = StackTracker::new();
+        stack.number_u32(0x12345678);
+        stack.to_altstack();
+        stack.number_u32(0x12345678);
+        stack.equalverify_from_altstack(3);
+    }
+
+    #[test]
+    #[should_panic(expected = "The top 2 elements of the altstack are not aligned with the variables")]
+    fn test_equalverify_from_altstack_not_aligned_altstack() {
+        let mut stack = StackTracker::new();
+        stack.number_u32(0x12345678);
+        stack.to_altstack();
+        stack.number(1);
+        stack.number(2);
+        stack.equalverify_from_altstack(2);
+    }
+
+    #[test]
+    fn test_equalverify_adjacent() {
+        let mut stack = StackTracker::new();
+        let x = stack.number_u32(0x12345678);
+        let y = stack.number_u32(0x12345678);
+        stack.equalverify_adjacent(x, y);
+        assert!(stack.data.stack.is_empty());
+        stack.op_true();
+        assert!(stack.run().success);
+
+        let mut stack2 = StackTracker::new();
+        let mut x = stack2.number_u32(0x12345678);
+        let mut y = stack2.number_u32(0x12345678);
+        stack2.equals(&mut x, true, &mut y, true);
+        stack2.op_true();
+        assert!(stack.get_script().len() < stack2.get_script().len());
+
+        let mut stack = StackTracker::new();
+        let x = stack.number_u32(0x12345678);
+        let y = stack.number_u32(0x12345679);
+        stack.equalverify_adjacent(x, y);
+        stack.op_true();
+        assert!(!stack.run().success);
+    }
+
+    #[test]
+    fn test_join() {
+        let mut stack = StackTracker::new();
+        let mut x = stack.number_u32(0xdeadbeaf);
+        let _y = stack.number_u32(0x12345678);
+        stack.join(&mut x);
+        let _  = stack.number_u32(0x00000000);
+        
+        stack.move_var(x);
+
+
+        let script = script! {
+            { stack.get_script()}
+            { number_to_nibble(0xdeadbeaf) }
+            { number_to_nibble(0x12345678) }
+            { verify_n(16) }
+            { drop_count(8) }
+            OP_TRUE
+        };
+
+        let (ret,_) = debug_script(script);
+        assert!(ret.result().unwrap().success);
+    }
+
+
+    #[test]
+    fn test_join_be_le() {
+        // already in order
+        let mut stack = StackTracker::new();
+        let high = stack.number_u16(0x1234);
+        let low = stack.number_u16(0x5678);
+        let len = stack.get_script_len();
+        let mut joined = stack.join_be(high, low);
+        assert_eq!(stack.get_script_len(), len);
+        assert_eq!(joined, StackVariable::new(high.id(), 8));
+        let mut expected = stack.number_u32(0x12345678);
+        stack.equals(&mut joined, true, &mut expected, true);
+        stack.op_true();
+        assert!(stack.run().success);
+
+        // the other way needs to reorder them
+        let mut stack = StackTracker::new();
+        let low = stack.number_u16(0x1234);
+        let high = stack.number_u16(0x5678);
+        let len = stack.get_script_len();
+        let mut joined = stack.join_be(high, low);
+        assert_eq!(stack.get_script_len(), len + 1);
+        let mut expected = stack.number_u32(0x56781234);
+        stack.equals(&mut joined, true, &mut expected, true);
+        stack.op_true();
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new();
+        let low = stack.number_u16(0x1234);
+        let high = stack.number_u16(0x5678);
+        let mut joined = stack.join_le(low, high);
+        assert_eq!(joined, StackVariable::new(low.id(), 8));
+        assert_eq!(stack.get_var_name(joined), "number_u16(0x1234)");
+        let mut expected = stack.number_u32(0x56781234);
+        stack.equals(&mut joined, true, &mut expected, true);
+        stack.op_true();
+        assert!(stack.run().success);
+
+        // not adjacent
+        let mut stack = StackTracker::new();
+        let high = stack.number_u16(0x1234);
+        stack.number(1);
+        let low = stack.number_u16(0x5678);
+        let mut joined = stack.join_le(low, high);
+        let mut expected = stack.number_u32(0x12345678);
+        stack.equals(&mut joined, true, &mut expected, true);
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_join_left() {
+        let mut stack = StackTracker::new();
+        let mut a = stack.number(1);
+        let b = stack.number(2);
+        let mut c = stack.number(3);
+
+        stack.join(&mut a);
+        assert_eq!(a.size, 2);
+        assert_eq!(stack.data.stack, vec![a, c]);
+        assert_eq!(stack.get_var_name(a), "number(0x1)");
+
+        stack.join_left(&mut c);
+        assert_eq!(c.size, 3);
+        assert_eq!(stack.data.stack, vec![c]);
+        assert_eq!(stack.get_var_name(c), "number(0x3)");
+        assert!(!stack.data.names.contains_key(&a.id));
+        assert!(!stack.data.names.contains_key(&b.id));
+
+        let mut expected = stack.number(1);
+        stack.number(2);
+        stack.number(3);
+        stack.join_count(&mut expected, 2);
+        stack.equals(&mut c, true, &mut expected, true);
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    #[should_panic(expected = "there is no variable below to join")]
+    fn test_join_left_first() {
+        let mut stack = StackTracker::new();
+        let mut a = stack.number(1);
+        stack.number(2);
+        stack.join_left(&mut a);
+    }
+
+    #[test]
+    #[should_panic(expected = "is outdated")]
+    fn test_join_outdated() {
+        let mut stack = StackTracker::new();
+        let mut a = stack.number(1);
+        let mut stale = a;
+        stack.number(2);
+        stack.number(3);
+        stack.join(&mut a);
+        stack.join(&mut stale);
+    }
+
+    #[test]
+    fn test_join_in_stack() {
+        let mut stack = StackTracker::new();
+        let x = stack.number_u32(0xdeadbeaf);
+        stack.explode(x);
+        let mut a = stack.join_in_stack(7, 4, Some("first-half"));
+        let mut b = stack.join_in_stack(3, 4, Some("second-half"));
+
+        let mut bb = stack.number_u16(0xbeaf);
+        stack.equals(&mut b, true, &mut bb, true);
+
+        let mut aa = stack.number_u16(0xdead);
+        stack.equals(&mut a, true, &mut aa, true);
+
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_explode_and_join_in_stack() {
+        let mut stack = StackTracker::new();
+        let x = stack.number_u32(0xdeadbeaf);
+        let y = stack.number_u32(0x0);
+        stack.explode(x);
+        stack.debug();
+        let mut a = stack.join_in_stack(7 + 8, 4, Some("first-half"));
+        stack.debug();
+        let mut b = stack.join_in_stack(3 + 8, 4, Some("second-half"));
+        stack.debug();
+
+        stack.drop(y);
+
+        let mut bb = stack.number_u16(0xbeaf);
+        stack.equals(&mut b, true, &mut bb, true);
+
+        let mut aa = stack.number_u16(0xdead);
+        stack.equals(&mut a, true, &mut aa, true);
+
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_explode_names() {
+        let mut stack = StackTracker::new();
+        let x = stack.number_u32(0x12345678);
+        stack.rename(x, "x");
+        let parts = stack.explode(x);
+        assert!(!stack.data.names.contains_key(&x.id));
+        assert_eq!(stack.data.names.len(), 8);
+        for (i, part) in parts.iter().enumerate() {
+            assert_eq!(stack.get_var_name(*part), format!("x[{}]", i));
+        }
+
+        // round trip
+        let mut first = parts[0];
+        stack.join_count(&mut first, 7);
+        assert_eq!(stack.data.names.len(), 1);
+        assert_eq!(stack.get_var_name(first), "x[0]");
+        let mut expected = stack.number_u32(0x12345678);
+        stack.equals(&mut first, true, &mut expected, true);
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_explode() {
+        let mut stack = StackTracker::new();
+        let x = stack.number_u32(0xdeadbeaf);
+        let orginal_size = stack.get_max_stack_size();
+        let x_parts = stack.explode(x);
+        let new_size = stack.get_max_stack_size();
+        assert_eq!(orginal_size, new_size);
+        stack.debug();
+        let temp = stack.copy_var(x_parts[1]);
+        stack.debug();
+        stack.drop(temp);
+
+        stack.move_var(x_parts[2]);
+
+        let script = script! {
+            { stack.get_script()}
+            OP_10
+            { verify_n(1) }
+            { drop_count(7) }
+            OP_TRUE
+        };
+
+        let (ret,_) = debug_script(script);
+        assert!(ret.result().unwrap().success);
+    }
+
+    #[test]
+    fn test_repeat_indexed() {
+        let mut stack = StackTracker::new();
+        let table = stack.var(3, script!{ 30 20 10 }, "table");
+        stack.repeat_indexed(3, |stack, _| {
+            stack.get_value_from_table(table, None);
+        });
+        for expected in [30, 20, 10] {
+            stack.number(expected);
+            stack.op_equalverify();
+        }
+
+        // the index is dropped when the body doesn't consume it
+        stack.repeat_indexed(2, |stack, index| {
+            assert!(stack.get_var_name(index).starts_with("index("));
+            stack.number(1);
+        });
+        stack.op_add();
+        stack.number(2);
+        stack.op_equalverify();
+        assert_eq!(stack.get_var_from_stack(0), table);
+        stack.drop(table);
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_get_from_table() {
+        //one element table
+        let mut stack = StackTracker::new();
+        let x = stack.number(123);
+        stack.number(0);
+        stack.get_value_from_table(x, None);
+        stack.number(123);
+        stack.op_equalverify();
+        stack.drop(x);
+        stack.op_true();
+        assert!(stack.run().success);
+
+
+        //two element tables
+        let mut stack = StackTracker::new();
+        let x = stack.var(2, script!{ OP_15 OP_8}, "small table");
+
+        stack.number(0);
+        stack.get_value_from_table(x, None);
+        stack.number(8);
+        stack.op_equalverify();
+
+        stack.number(1);
+        stack.get_value_from_table(x, None);
+        stack.number(15);
+        stack.op_equalverify();
+
+        stack.drop(x);
+        stack.op_true();
+        assert!(stack.run().success);
+
+    }
+
+
+    #[test]
+    fn test_get_from_table_checked() {
+        let mut stack = StackTracker::new();
+        let table = stack.var(3, script!{ 30 20 10 }, "table");
+        stack.get_value_from_table_at(table, None, 3, 2);
+        stack.number(30);
+        stack.op_equalverify();
+        stack.number(1);
+        stack.get_value_from_table_checked(table, None, 3);
+        stack.number(20);
+        stack.op_equalverify();
+        stack.drop(table);
+        stack.op_true();
+        assert!(stack.run().success);
+
+        // the guard fails the script at runtime (without it the element below the table would be read)
+        let build = |checked: bool| {
+            let mut stack = StackTracker::new();
+            let below = stack.number(40);
+            let table = stack.var(3, script!{ 30 20 10 }, "table");
+            stack.number(3);
+            if checked {
+                stack.get_value_from_table_checked(table, None, 3);
+            } else {
+                stack.get_value_from_table(table, None);
+            }
+            stack.op_drop();
+            stack.drop(table);
+            stack.drop(below);
+            stack.op_true();
+            stack
+        };
+        assert!(build(false).run().success);
+        assert!(build(true).run().error);
+    }
+
+    #[test]
+    #[should_panic(expected = "The index 3 is out of range")]
+    fn test_get_from_table_at_out_of_range() {
+        let mut stack = StackTracker::new();
+        let table = stack.var(3, script!{ 30 20 10 }, "table");
+        stack.get_value_from_table_at(table, None, 3, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "has been partially consumed")]
+    fn test_get_from_table_partially_consumed() {
+        let mut stack = StackTracker::new();
+        let mut table = stack.var(3, script!{ 30 20 10 }, "table");
+        let original = table;
+        stack.move_var_sub_n(&mut table, 0);
+        stack.number(0);
+        stack.get_value_from_table(original, None);
+    }
+
+    #[test]
+    fn test_equalverify_error() {
+        let mut stack = StackTracker::new();
+        stack.number(3);
+        stack.number(1);
+        stack.number(1);
+        stack.op_add();
+        stack.op_equalverify();
+        stack.op_true();
+        let ret = stack.run();
+        assert!(ret.error);
+        assert!(ret.error_msg.ends_with("OP_EQUALVERIFY() at step 4 failed comparing number(0x3) (3) with (number(0x1) + number(0x1)) (2)"), "{}", ret.error_msg);
+
+        // other failures are not explained
+        let mut stack = StackTracker::new();
+        stack.number(0);
+        stack.op_verify();
+        stack.op_true();
+        let ret = stack.run();
+        assert!(ret.error);
+        assert!(!ret.error_msg.contains("failed comparing"));
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        let b = stack.number_u32(0x12345678);
+        let c = stack.number(3);
+        let d = stack.number(4);
+        let e = stack.number(5);
+
+        // a, c and e are already in order
+        let len = stack.get_script_len();
+        stack.compact(&[a, c, e, d, b]);
+        assert_eq!(stack.data.stack, vec![a, c, e, d, b]);
+        assert_eq!(stack.get_script_len(), len + 2);
+
+        stack.compact(&[b, a, c, d, e]);
+        assert_eq!(stack.data.stack, vec![b, a, c, d, e]);
+
+        let len = stack.get_script_len();
+        stack.compact(&[b, a, c, d, e]);
+        assert_eq!(stack.get_script_len(), len);
+
+        for (var, value) in [(e, 5), (d, 4), (c, 3), (a, 1)] {
+            stack.number(value);
+            let mut top = stack.get_var_from_stack(0);
+            let mut var = var;
+            stack.equals(&mut var, true, &mut top, true);
+        }
+        let mut expected = stack.number_u32(0x12345678);
+        let mut b = b;
+        stack.equals(&mut b, true, &mut expected, true);
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    #[should_panic(expected = "every variable of the stack once")]
+    fn test_compact_missing() {
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        stack.number(2);
+        stack.compact(&[a]);
+    }
+
+    #[test]
+    fn test_operands_name() {
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        stack.rename(a, "a");
+        let b = stack.number(2);
+        stack.rename(b, "b");
+        let sum = stack.op_add();
+        assert_eq!(stack.get_var_name(sum), "(a + b)");
+        assert_eq!(stack.step_label(stack.get_script_len() - 1), Some("OP_ADD()"));
+
+        stack.number(3);
+        let cmp = stack.op_lessthan();
+        assert_eq!(stack.get_var_name(cmp), "((a + b) < number(0x3))");
+
+        // the names don't grow with chained operations
+        let mut x = stack.number(1);
+        for _ in 0..20 {
+            stack.copy_var(x);
+            x = stack.op_add();
+        }
+        assert!(stack.get_var_name(x).len() <= 2 * MAX_OPERAND_NAME_LEN + 5);
+        assert!(stack.get_var_name(x).contains('\u{2026}'));
+        stack.drop(x);
+
+        stack.drop(cmp);
+
+        // operands without name
+        stack.number(1);
+        stack.custom(script!{ OP_1 }, 0, true, 0, "");
+        let min = stack.op_min();
+        assert_eq!(stack.get_var_name(min), "OP_MIN()");
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_copy_altstack_slot() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.number_u32(2);
+        stack.number(3);
+        stack.to_altstack_count(3);
+        let altstack = stack.data.altstack.clone();
+
+        let copy = stack.copy_altstack_slot(1);
+        assert_eq!(copy.size(), altstack[1].size());
+        assert_eq!(stack.data.altstack, altstack);
+        let mut copy = copy;
+        let mut expected = stack.number_u32(2);
+        stack.equals(&mut copy, true, &mut expected, true);
+
+        let vars = stack.from_altstack_count(3);
+        for var in vars.into_iter().rev() {
+            stack.drop(var);
+        }
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    #[should_panic(expected = "there is no slot at depth 1")]
+    fn test_copy_altstack_slot_out_of_range() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.to_altstack();
+        stack.copy_altstack_slot(1);
+    }
+
+    #[test]
+    fn test_try_run() -> Result<(), ScriptFailure> {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.try_run()?;
+
+        stack.number(2);
+        stack.op_equalverify();
+        stack.op_true();
+        let failure = stack.try_run().unwrap_err();
+        assert_eq!(failure.step, 2);
+        assert_eq!(failure.label, "OP_EQUALVERIFY()");
+        assert_eq!(failure.last_opcode, "OP_EQUALVERIFY");
+        assert!(failure.error_msg.contains("comparing number(0x1) (1) with number(0x2) (2)"), "{}", failure);
+
+        let mut stack = StackTracker::new();
+        stack.number(0);
+        let failure = stack.try_run().unwrap_err();
+        assert_eq!(failure.step, 0);
+        assert_eq!(failure.label, "number(0x0)");
+        Ok(())
+    }
+
+    #[test]
+    fn test_broadcast_to_altstack() {
+        let mut stack = StackTracker::new();
+        let x = stack.number(7);
+        let copies = stack.broadcast_to_altstack(x, 4);
+        assert_eq!(copies.len(), 4);
+        assert_eq!(stack.data.altstack.len(), 4);
+        assert_eq!(stack.data.altstack.last().unwrap().id, copies[0].id);
+        assert_eq!(stack.get_var_name(copies[0]), "broadcast[0]");
+        assert_eq!(stack.get_var_from_stack(0), x);
+
+        for _ in 0..4 {
+            stack.from_altstack();
+            stack.copy_var(x);
+            stack.op_equalverify();
+        }
+        stack.drop(x);
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_copy_var_times() {
+        let build = |times: bool| {
+            let mut stack = StackTracker::new();
+            let x = stack.number(7);
+            let pair = stack.number_u16(0x1234);
+            for i in 0..10 {
+                stack.number(i);
+            }
+            let copies = if times {
+                stack.copy_var_times(x, 5)
+            } else {
+                (0..5).map(|_| stack.copy_var(x)).collect()
+            };
+            assert_eq!(copies.len(), 5);
+            let pairs = stack.copy_var_times(pair, 3);
+            for mut copy in pairs.into_iter().rev() {
+                stack.number_u16(0x1234);
+                let mut expected = stack.get_var_from_stack(0);
+                stack.equals(&mut copy, true, &mut expected, true);
+            }
+            for mut copy in copies.into_iter().rev() {
+                let mut expected = stack.number(7);
+                stack.equals(&mut copy, true, &mut expected, true);
+            }
+            stack
+        };
+
+        let stack = build(true);
+        assert!(stack.get_script().len() < build(false).get_script().len());
+        let mut stack = stack;
+        for _ in 0..12 {
+            stack.drop(stack.get_var_from_stack(0));
+        }
+        stack.op_true();
+        assert!(stack.run().success);
+        assert_eq!(StackTracker::new().copy_var_times(StackVariable::null(), 0), vec![]);
+    }
+
+    #[test]
+    fn test_equals_and_verify() {
+        let mut stack = StackTracker::new();
+        let a = stack.number_u32(0x12345678);
+        stack.number(1);
+        let b = stack.number_u32(0x12345678);
+        stack.equals_and_verify(a, b);
+        let size = a.size() as usize;
+        let opcodes = stack.script_chunks().last().unwrap().instructions().count();
+        assert_eq!(opcodes, 3 + 5 * (size - 1) + 1);
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new();
+        let a = stack.number_u32(0x12345678);
+        let b = stack.number_u32(0x12345679);
+        stack.equals_and_verify(a, b);
+        stack.op_true();
+        assert!(stack.run().error);
+    }
+
+    #[test]
+    fn test_equals_adjacent_consumed() {
+        let mut stack = StackTracker::new();
+        let mut a = stack.number_u32(0x1234);
+        let mut b = stack.number_u32(0x1234);
+        let before = stack.get_script().len();
+        stack.equals(&mut b, true, &mut a, true);
+        assert_eq!((a.size(), b.size()), (0, 0));
+        let script = stack.get_script();
+        assert_eq!(script.as_bytes()[before..], verify_n(8).as_bytes()[..]);
+        assert!(stack.data.stack.is_empty());
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_equals_many() {
+        let build = |scheduled: bool, consume: bool| {
+            let mut stack = StackTracker::new();
+            let a = (0..4).map(|i| stack.number(i * 3)).collect::<Vec<_>>();
+            let b = (0..4).map(|i| stack.number(i * 3)).collect::<Vec<_>>();
+            let pairs = a.into_iter().zip(b).collect::<Vec<_>>();
+            if scheduled {
+                stack.equals_many(&pairs, consume);
+            } else {
+                for (mut x, mut y) in pairs {
+                    stack.equals(&mut x, consume, &mut y, consume);
+                }
+            }
+            stack
+        };
+
+        for consume in [true, false] {
+            let mut stack = build(true, consume);
+            let opcodes = stack.get_script().instructions().count();
+            let naive = build(false, consume).get_script().instructions().count();
+            // the copies are picked with the same opcodes at any depth
+            assert!(opcodes < naive || (!consume && opcodes == naive));
+            if !consume {
+                for _ in 0..8 {
+                    stack.drop(stack.get_var_from_stack(0));
+                }
+            }
+            stack.op_true();
+            assert!(stack.run().success);
+        }
+
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        let b = stack.number(2);
+        stack.equals_many(&[(a, b)], true);
+        stack.op_true();
+        assert!(!stack.run().success);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not big enough to copy n=1")]
+    fn test_copy_var_sub_n_out_of_range() {
+        let mut stack = StackTracker::new();
+        let x = stack.number(1);
+        // it used to compute the depth -1 (u32::MAX)
+        stack.copy_var_sub_n(x, 1);
+    }
+
+    #[test]
+    fn test_keep_top() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.number_u32(0x12345678);
+        stack.number(2);
+        let result = stack.number_u32(0xcafe);
+        let flag = stack.number(1);
+        stack.keep_top(2);
+        assert_eq!(stack.data.stack, vec![result, flag]);
+        assert!(stack.data.altstack.is_empty());
+
+        stack.op_verify();
+        let mut expected = stack.number_u32(0xcafe);
+        stack.equals(&mut expected, true, &mut result.clone(), true);
+        stack.keep_top(0);
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_reverse_altstack() {
+        let mut stack = StackTracker::new();
+        let vars = (1..=3).map(|i| {
+            let var = stack.number(i);
+            stack.to_altstack();
+            var
+        }).collect::<Vec<_>>();
+        stack.reverse_altstack();
+        assert_eq!(stack.data.altstack, vars.iter().rev().cloned().collect::<Vec<_>>());
+
+        for i in 1..=3 {
+            stack.from_altstack();
+            stack.number(i);
+            stack.op_equalverify();
+        }
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_sum_altstack() {
+        let mut stack = StackTracker::new();
+        stack.number(100);
+        stack.to_altstack();
+        for i in [3, 5, 7, 11, 13] {
+            stack.number(i);
+            stack.to_altstack();
+        }
+        stack.sum_altstack(5);
+        assert_eq!(stack.data.altstack.len(), 1);
+        stack.number(39);
+        stack.op_equalverify();
+        stack.from_altstack();
+        stack.number(100);
+        stack.op_equal();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stack = StackTracker::new();
+        let vars = (1..=5).map(|i| stack.number(i)).collect::<Vec<_>>();
+        stack.move_var(vars[0]);
+        stack.copy_var(vars[1]);
+        stack.copy_var(vars[3]);
+        stack.move_var(vars[2]);
+        stack.op_swap();
+        let stats = stack.stats();
+        assert_eq!(stats.total_rolls, 2);
+        assert_eq!(stats.total_picks, 2);
+        assert_eq!(stats.max_roll_depth, 5);
+        assert_eq!(stats.max_stack_size, 7);
+        assert_eq!(stats.script_size, stack.get_script().len());
+        assert_eq!(StackTracker::new().stats(), Stats::default());
+
+        // hash pushes are not numbers
+        let mut stack = StackTracker::new();
+        let hash = stack.hexstr(&"ab".repeat(32));
+        stack.number(1);
+        stack.copy_var(hash);
+        stack.move_var(hash);
+        let stats = stack.stats();
+        assert_eq!(stats.total_picks, 1);
+        assert_eq!(stats.total_rolls, 1);
+        assert_eq!(stats.max_roll_depth, 2);
+    }
+
+    #[test]
+    fn test_bind() {
+        let mut stack = StackTracker::new();
+        let acc = stack.number(1);
+        let handle = stack.bind(acc, "accumulator");
+        assert_eq!(handle.var(&stack), acc);
+
+        // the bound variable absorbs another one
+        stack.number(2);
+        let mut joined = acc;
+        stack.join(&mut joined);
+        assert_eq!(stack.resolve("accumulator"), joined);
+        assert_eq!(stack.resolve("accumulator").size(), 2);
+
+        // the bound variable is absorbed by another one
+        let low = stack.number(3);
+        let acc = stack.join_le(low, stack.resolve("accumulator"));
+        assert_eq!(stack.resolve("accumulator"), acc);
+        assert_eq!(acc.size(), 3);
+
+        stack.to_altstack();
+        assert_eq!(handle.var(&stack), acc);
+    }
+
+    #[test]
+    fn test_bind_rollback() {
+        let mut stack = StackTracker::new();
+        let acc = stack.number(1);
+        stack.bind(acc, "accumulator");
+
+        // the join moves the role to another id that is discarded by the rollback
+        let checkpoint = stack.checkpoint();
+        let low = stack.number(2);
+        let joined = stack.join_le(low, acc);
+        assert_ne!(joined.id(), acc.id());
+        assert_eq!(stack.resolve("accumulator"), joined);
+        stack.rollback(checkpoint);
+        assert_eq!(stack.resolve("accumulator"), acc);
+
+        // the discarded id is reused by an unrelated variable
+        let other = stack.number(6);
+        assert_eq!(other.id(), joined.id());
+        assert_eq!(stack.resolve("accumulator"), acc);
+    }
+
+    #[test]
+    #[should_panic(expected = "bound to the role accumulator is not on the stack anymore")]
+    fn test_bind_exploded() {
+        let mut stack = StackTracker::new();
+        let acc = stack.number_u32(1);
+        stack.bind(acc, "accumulator");
+        stack.explode(acc);
+        stack.resolve("accumulator");
+    }
+
+    #[test]
+    fn test_explode_to_altstack() {
+        let mut stack = StackTracker::new();
+        let x = stack.number_u32(0x12345678);
+        let parts = stack.explode_to_altstack(x);
+        assert!(stack.data.stack.is_empty());
+        assert_eq!(parts.len(), stack.data.altstack.len());
+        assert_eq!(*stack.data.altstack.last().unwrap(), parts[0]);
+
+        stack.number(2);
+        stack.number(3);
+        stack.op_add();
+        stack.number(5);
+        stack.op_equalverify();
+
+        let mut x = stack.reassemble_from_altstack(&parts, "x");
+        assert_eq!(stack.get_var_name(x), "x");
+        assert_eq!(x.size(), parts.len() as u32);
+        let mut expected = stack.number_u32(0x12345678);
+        stack.equals(&mut x, true, &mut expected, true);
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_move_var_to_altstack() {
+        let mut stack = StackTracker::new();
+        let x = stack.number_u32(0x12345678);
+        let y = stack.number(7);
+        let moved = stack.move_var_to_altstack(x);
+        assert_eq!(moved, x);
+        assert_eq!(stack.data.stack, vec![y]);
+        assert_eq!(stack.data.altstack, vec![x]);
+
+        let mut x = stack.from_altstack();
+        let mut expected = stack.number_u32(0x12345678);
+        stack.equals(&mut x, true, &mut expected, true);
+        stack.number(7);
+        stack.op_equal();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_select() {
+        for selector in [0, 1, 2] {
+            let mut stack = StackTracker::new();
+            // already in place, no moves are needed
+            let b = stack.number(9);
+            let a = stack.number(5);
+            let sel = stack.number(selector);
+            let selected = stack.select(sel, a, b);
+            assert_eq!(stack.data.stack, vec![selected]);
+            assert_eq!(stack.get_script().instructions().count(), 3 + 4);
+            stack.number(if selector != 0 { 5 } else { 9 });
+            stack.op_equal();
+            assert!(stack.run().success);
+
+            let mut stack = StackTracker::new();
+            let sel = stack.number(selector);
+            let a = stack.number_u32(0x12345678);
+            let b = stack.number_u32(0xcafe);
+            let mut selected = stack.select(sel, a, b);
+            assert_eq!(selected.size(), a.size());
+            let mut expected = stack.number_u32(if selector != 0 { 0x12345678 } else { 0xcafe });
+            stack.equals(&mut selected, true, &mut expected, true);
+            stack.op_true();
+            assert!(stack.run().success);
+        }
+    }
+
+    #[test]
+    fn test_custom_ex_outputs_and_altstack() {
+        let mut stack = StackTracker::new();
+        stack.number(5);
+        let ret = stack.custom_ex(script!{ OP_DUP OP_TOALTSTACK OP_1ADD }, 1, vec![(1, "next".to_string())], 1);
+        assert_eq!(ret.len(), 1);
+        assert_eq!(stack.data.stack, ret);
+        assert_eq!(stack.data.altstack.len(), 1);
+
+        stack.number(6);
+        stack.op_equalverify();
+        stack.from_altstack();
+        stack.number(5);
+        stack.op_equal();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_normalize_ids() {
+        let build = |scratch: u32| {
+            let mut stack = StackTracker::new();
+            for i in 0..scratch {
+                let x = stack.number(i);
+                stack.drop(x);
+            }
+            let x = stack.number_u32(0x1234);
+            let y = stack.number(5);
+            stack.copy_var(x);
+            stack.to_altstack();
+            stack.join_be(x, y);
+            stack.normalize_ids();
+            stack
+        };
+        let (a, b) = (build(0), build(3));
+        assert_ne!(a.counter, b.counter);
+        assert_eq!(a.data.stack, b.data.stack);
+        assert_eq!(a.data.altstack, b.data.altstack);
+        let names = |stack: &StackTracker| stack.data.stack.iter().chain(stack.data.altstack.iter())
+            .map(|v| stack.get_var_name(*v)).collect::<Vec<_>>();
+        assert_eq!(names(&a), names(&b));
+        assert_eq!(a.data.stack[0].id(), 1);
+        assert_eq!(a.data.altstack[0].id(), 2);
+
+        // the model of the steps and the lint follow the new ids
+        let mut b = b;
+        b.from_altstack();
+        b.drop(b.get_var_from_stack(0));
+        let unused = b.lint().into_iter().map(|w| match w {
+            LintWarning::UnusedVariable { var, name } => (var.id(), name),
+            w => panic!("unexpected {}", w),
+        }).collect::<Vec<_>>();
+        assert_eq!(unused, vec![(3, "number(0x0)".to_string()), (4, "number(0x1)".to_string()), (5, "number(0x2)".to_string()),
+            (2, "copy(number_u32(0x1234))".to_string())]);
+        let last = b.data.new_from_redo_height(b.history[b.get_script_len() - 1] as usize);
+        assert_eq!(last.stack, b.data.stack);
+    }
+
+    #[test]
+    fn test_negate_u32() {
+        for base in [Base::Nibble, Base::Byte] {
+            for value in [0, 1, 0x80000000, 0xfedcba98, 0xffffffff] {
+                let mut stack = StackTracker::new_with_base(base);
+                let x = stack.number_u32(value);
+                stack.number(1);
+                let mut negated = stack.negate_u32(x);
+                assert_eq!(stack.get_var_name(negated), format!("negate(number_u32({:#x}))", value));
+                let mut expected = stack.number_u32(value.wrapping_neg());
+                stack.equals(&mut negated, true, &mut expected, true);
+                assert!(stack.run().success, "{:#x}", value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_can_join() {
+        let mut stack = StackTracker::new();
+        let mut x = stack.number(1);
+        assert!(!stack.can_join(x));
+        let y = stack.number(2);
+        assert!(stack.can_join(x));
+        assert!(!stack.can_join(y));
+
+        // outdated size
+        let old = x;
+        stack.join(&mut x);
+        stack.number(3);
+        assert!(!stack.can_join(old));
+        assert!(stack.can_join(x));
+
+        // on the altstack and consumed
+        stack.to_altstack();
+        stack.to_altstack();
+        assert!(!stack.can_join(x));
+        stack.from_altstack();
+        stack.drop(x);
+        assert!(!stack.can_join(x));
+        assert!(!stack.can_join(y));
+    }
+
+    #[test]
+    #[should_panic(expected = "is on top of the stack, there is no variable above to join")]
+    fn test_join_top() {
+        let mut stack = StackTracker::new();
+        let mut x = stack.number(1);
+        stack.join(&mut x);
+    }
+
+    #[test]
+    #[should_panic(expected = "is on the altstack, use from_altstack first")]
+    fn test_join_altstack() {
+        let mut stack = StackTracker::new();
+        let mut x = stack.number(1);
+        stack.number(2);
+        stack.move_var_to_altstack(x);
+        stack.join(&mut x);
+    }
+
+    #[test]
+    fn test_is_member() {
+        for (value, expected) in [(3, 1), (-1, 1), (1000, 1), (0, 0), (4, 0), (-3, 0)] {
+            let mut stack = StackTracker::new();
+            stack.number_exact(value);
+            stack.is_member(&[3, -1, 1000], true);
+            assert_eq!(stack.data.stack.len(), 1);
+            stack.number(expected);
+            stack.op_equal();
+            assert!(stack.run().success, "{} in the set should be {}", value, expected);
+        }
+
+        let mut stack = StackTracker::new();
+        let x = stack.number(5);
+        let flag = stack.is_member(&[5], false);
+        assert_eq!(stack.data.stack, vec![x, flag]);
+        stack.op_verify();
+        stack.number(5);
+        stack.op_equal();
+        assert!(stack.run().success);
+    }
+
+    fn countdown(start: u32, max_iters: u32) -> StackTracker {
+        let mut stack = StackTracker::new();
+        stack.number(start);
+        stack.bounded_while(max_iters, |s| { s.op_1sub(); }, |s| {
+            s.op_dup();
+            s.gt_const(3)
+        });
+        stack
+    }
+
+    #[test]
+    fn test_bounded_while() {
+        let mut stack = countdown(10, 10);
+        assert_eq!(stack.data.stack.len(), 1);
+        stack.number(3);
+        stack.op_equal();
+        assert!(stack.run().success);
+
+        // the condition is false from the start
+        let mut stack = countdown(2, 5);
+        stack.number(2);
+        stack.op_equal();
+        assert!(stack.run().success);
+
+        // stops at max_iters
+        let mut stack = countdown(10, 4);
+        stack.number(6);
+        stack.op_equal();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    #[should_panic(expected = "same sizes that it consumes")]
+    fn test_bounded_while_shape() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.bounded_while(2, |s| { s.op_dup(); }, |s| s.op_dup());
+    }
+
+    #[test]
+    fn test_script_chunks() {
+        let mut stack = StackTracker::new();
+        let x = stack.number_u32(0x12345678);
+        stack.hexstr("0102030405");
+        stack.move_var(x);
+        stack.number(1);
+        stack.number(2);
+        stack.op_add();
+
+        assert_eq!(stack.script_chunks().count(), stack.get_script_len());
+        let cloned = script! {
+            for s in stack.script.iter() {
+                { s.clone() }
+            }
+        };
+        assert_eq!(concat_scripts(stack.script_chunks()), cloned);
+        assert_eq!(stack.get_script(), cloned);
+        assert_eq!(concat_scripts(stack.script_chunks().skip(1).take(2)), stack.get_script_range(1, 2));
+    }
+
+    #[test]
+    fn test_altstack_table() {
+        let mut stack = StackTracker::new();
+        stack.number(99);
+        stack.to_altstack();
+        let table = stack.build_altstack_table(&[10, 20, 30, 40]);
+        assert!(stack.data.stack.is_empty());
+
+        let index = stack.number(2);
+        let value = stack.get_from_altstack_table(table, index);
+        assert_eq!(stack.data.stack, vec![value]);
+        assert_eq!(stack.data.altstack.last(), Some(&table));
+        stack.number(30);
+        stack.op_equalverify();
+
+        let index = stack.number(0);
+        stack.get_from_altstack_table(table, index);
+        stack.number(10);
+        stack.op_equalverify();
+
+        stack.from_altstack();
+        stack.drop(table);
+        stack.from_altstack();
+        stack.number(99);
+        stack.op_equal();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_select_from_table_if() {
+        let mut stack = StackTracker::new();
+        let table = stack.var(4, script!{ OP_4 OP_3 OP_2 OP_1 }, "table");
+
+        for i in 0..4 {
+            let index = stack.number(i);
+            stack.select_from_table_if(table, index);
+            stack.number(i);
+            stack.get_value_from_table(table, None);
+            stack.op_equalverify();
+        }
+
+        stack.drop(table);
         stack.op_true();
         assert!(stack.run().success);
+    }
 
-
-        //two element tables
+    #[test]
+    fn test_select_from_table_if_max_size() {
         let mut stack = StackTracker::new();
-        let x = stack.var(2, script!{ OP_15 OP_8}, "small table");
-
-        stack.number(0);
-        stack.get_value_from_table(x, None);
-        stack.number(8);
-        stack.op_equalverify();
-
-        stack.number(1);
-        stack.get_value_from_table(x, None);
-        stack.number(15);
+        assert_eq!(stack.get_max_table_if_size(), 16);
+        stack.set_max_table_if_size(20);
+        let table = stack.var(20, script!{ for i in (0..20).rev() { { i } } }, "table");
+        let index = stack.number(17);
+        stack.select_from_table_if(table, index);
+        stack.number(17);
         stack.op_equalverify();
-
-        stack.drop(x);
+        stack.drop(table);
         stack.op_true();
         assert!(stack.run().success);
+    }
+
+    #[test]
+    #[should_panic(expected = "is bigger than 2")]
+    fn test_select_from_table_if_too_big() {
+        let mut stack = StackTracker::new();
+        stack.set_max_table_if_size(2);
+        let table = stack.var(4, script!{ OP_4 OP_3 OP_2 OP_1 }, "table");
+        let index = stack.number(0);
+        stack.select_from_table_if(table, index);
+    }
 
+    #[test]
+    fn test_null_id() {
+        let mut stack = StackTracker::new();
+        let var = stack.define(1, "first");
+        assert!(var.id() >= 1);
+        assert!(!var.is_null());
+        assert!(StackVariable::null().is_null());
+        assert!(!StackVariable::new(1, 0).is_null());
     }
 
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "reserved for StackVariable::null()")]
+    fn test_push_null() {
+        let mut data = StackData::new(true);
+        data.push_stack(StackVariable::null());
+    }
 
     #[test]
     fn test_redo_log() {
@@ -1311,6 +5031,117 @@ mod tests {
 
     }
 
+    fn check_const(value: i64, op: fn(&mut StackTracker, i64) -> StackVariable, k: i64, expected: bool) {
+        let mut stack = StackTracker::new();
+        stack.custom(script!{ { value } }, 0, true, 0, "value");
+        op(&mut stack, k);
+        if !expected {
+            stack.op_not();
+        }
+        assert_eq!(stack.data.stack.len(), 1);
+        assert!(stack.run().success, "failed comparing {} with {} (expected {})", value, k, expected);
+    }
+
+    #[test]
+    fn test_compare_const() {
+        for (value, k) in [(3, 5), (5, 5), (7, 5), (-3, -2), (-2, -2), (0, -1), (1000, 20000), (-20000, 1000)] {
+            check_const(value, StackTracker::lt_const, k, value < k);
+            check_const(value, StackTracker::le_const, k, value <= k);
+            check_const(value, StackTracker::gt_const, k, value > k);
+            check_const(value, StackTracker::ge_const, k, value >= k);
+            check_const(value, StackTracker::eq_const, k, value == k);
+        }
+    }
+
+    #[test]
+    fn test_within_const() {
+        for (value, expected) in [(0, true), (7, true), (15, true), (16, false), (-1, false), (100, false)] {
+            let mut stack = StackTracker::new();
+            stack.custom(script!{ { value } }, 0, true, 0, "value");
+            stack.within_const(0, 16);
+            if !expected {
+                stack.op_not();
+            }
+            assert!(stack.run().success, "failed checking {} (expected {})", value, expected);
+
+            let mut stack = StackTracker::new();
+            stack.custom(script!{ { value } }, 0, true, 0, "value");
+            stack.within_const_verify(0, 16);
+            assert!(stack.data.stack.is_empty());
+            stack.op_true();
+            assert_eq!(stack.run().success, expected);
+        }
+    }
+
+    fn permute_top_gadget(permutation: &[usize]) -> StackTracker {
+        let mut stack = StackTracker::new();
+        let vars = (0..permutation.len()).rev().map(|i| stack.number(i as u32)).collect::<Vec<StackVariable>>();
+        stack.permute_top(permutation);
+        let expected = permutation.iter().rev().map(|p| vars[permutation.len() - 1 - p]).collect::<Vec<StackVariable>>();
+        assert_eq!(stack.data.stack, expected);
+        for p in permutation {
+            stack.number(*p as u32);
+            stack.op_equalverify();
+        }
+        stack.op_true();
+        stack
+    }
+
+    #[test]
+    fn test_permute_top() {
+        let identity = permute_top_gadget(&[0, 1, 2, 3, 4, 5]);
+        assert!(identity.run().success);
+        assert_eq!(permute(&[0, 1, 2, 3, 4, 5]).len(), 0);
+
+        let reverse = permute_top_gadget(&[5, 4, 3, 2, 1, 0]);
+        assert!(reverse.run().success);
+        assert_eq!(permute(&[5, 4, 3, 2, 1, 0]).to_asm_string(), "OP_SWAP OP_ROT OP_PUSHNUM_3 OP_ROLL OP_PUSHNUM_4 OP_ROLL OP_PUSHNUM_5 OP_ROLL");
+
+        // 4 and 1 are already in order at the bottom, so only 4 rolls are needed
+        let random = permute_top_gadget(&[3, 5, 0, 2, 1, 4]);
+        assert!(random.run().success);
+        assert_eq!(permute(&[3, 5, 0, 2, 1, 4]).instructions().count(), 4 + 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "needs to be of size 1")]
+    fn test_permute_top_size() {
+        let mut stack = StackTracker::new();
+        stack.number_u32(1);
+        stack.number(2);
+        stack.permute_top(&[1, 0]);
+    }
+
+    #[test]
+    fn test_custom_reorder() {
+        let mut stack = StackTracker::new();
+        let z = stack.number(7);
+        let mut x = stack.number_u32(0x1234);
+        let mut y = stack.number(5);
+        stack.custom_reorder(move_from(1, 8), &[1, 0], "swap");
+        assert_eq!(stack.data.stack, vec![z, y, x]);
+
+        let mut xx = stack.number_u32(0x1234);
+        stack.equals(&mut x, true, &mut xx, true);
+        let mut yy = stack.number(5);
+        stack.equals(&mut y, true, &mut yy, true);
+        assert!(stack.run().success);
+
+        let mut stack2 = StackTracker::new();
+        let a = stack2.number(1);
+        let b = stack2.number(2);
+        let c = stack2.number(3);
+        stack2.custom_reorder(script!{ OP_ROT }, &[2, 0, 1], "rot");
+        assert_eq!(stack2.data.stack, vec![b, c, a]);
+        stack2.number(1);
+        stack2.op_equalverify();
+        stack2.number(3);
+        stack2.op_equalverify();
+        stack2.number(2);
+        stack2.op_equal();
+        assert!(stack2.run().success);
+    }
+
     #[test]
     fn test_op_rot() {
         let mut stack = StackTracker::new();
@@ -1468,6 +5299,95 @@ mod tests {
 
     }
    
+    #[test]
+    fn test_frame() {
+        let mut stack = StackTracker::new();
+        let below = stack.number(100);
+        let a = stack.number(1);
+        let b = stack.number(20);
+        let c = stack.number_u32(0x300);
+        let other = stack.number(7);
+
+        let frame = stack.push_frame(vec![b, a, c]);
+        assert_eq!(stack.data.stack, vec![below, other, b, a, c]);
+        assert_eq!(frame.len(), 3);
+
+        // 0x300 + 1 - 20 using the arguments out of order
+        let mut expected = stack.number_u32(0x300);
+        let mut arg = frame.arg(2);
+        stack.equals(&mut arg, false, &mut expected, true);
+        stack.copy_var(frame.arg(1));
+        stack.number(0x300);
+        stack.op_add();
+        stack.copy_var(frame.arg(0));
+        stack.op_sub();
+        let result = stack.to_altstack();
+
+        frame.drop_frame(&mut stack);
+        assert_eq!(stack.data.stack, vec![below, other]);
+        stack.from_altstack();
+        assert_eq!(stack.data.stack, vec![below, other, result]);
+        stack.number(0x300 + 1 - 20);
+        stack.op_equalverify();
+        stack.op_drop();
+        stack.number(100);
+        stack.op_equal();
+        assert!(stack.run().success);
+
+        // already in place
+        let mut stack = StackTracker::new();
+        let a = stack.number(1);
+        let b = stack.number(2);
+        let len = stack.get_script_len();
+        let frame = stack.push_frame(vec![a, b]);
+        assert_eq!(stack.get_script_len(), len);
+        stack.op_drop();
+        frame.drop_frame(&mut stack);
+        assert!(stack.data.stack.is_empty());
+    }
+
+    fn switch_gadget(selector: u32, expected: u32) -> StackTracker {
+        let mut stack = StackTracker::new();
+        stack.number(5);
+        let sel = stack.number(selector);
+        let cases: Vec<(i64, CaseBuilder)> = (1..=3).map(|k| {
+            let case: CaseBuilder = Box::new(move |stack: &mut StackTracker| {
+                stack.number(k as u32 * 10);
+                stack.op_add();
+                stack.rename(stack.get_var_from_stack(0), "result");
+            });
+            (k, case)
+        }).collect();
+        let result = stack.switch(sel, cases, Box::new(|stack: &mut StackTracker| {
+            stack.op_drop();
+            stack.number(99);
+        }));
+        assert_eq!(result.len(), 1);
+        assert_eq!(stack.get_var_name(result[0]), "result");
+        assert_eq!(stack.data.stack, result);
+        stack.number(expected);
+        stack.op_equal();
+        stack
+    }
+
+    #[test]
+    fn test_switch() {
+        assert!(switch_gadget(1, 15).run().success);
+        assert!(switch_gadget(2, 25).run().success);
+        assert!(switch_gadget(3, 35).run().success);
+        assert!(switch_gadget(4, 99).run().success);
+        assert!(switch_gadget(0, 99).run().success);
+        assert!(!switch_gadget(2, 15).run().success);
+    }
+
+    #[test]
+    #[should_panic(expected = "All the branches of the switch need to consume and produce the same variables")]
+    fn test_switch_shape() {
+        let mut stack = StackTracker::new();
+        let sel = stack.number(1);
+        stack.switch(sel, vec![(1, Box::new(|stack: &mut StackTracker| { stack.number(1); }))], Box::new(|_: &mut StackTracker| {}));
+    }
+
    #[test]
     fn test_open_if() {
         let mut stack = StackTracker::new();
@@ -1497,6 +5417,42 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_end_if_auto() {
+        let mut stack = StackTracker::new();
+        let keep = stack.number(7);
+        stack.number(5);
+        stack.number(2);
+        stack.number(1);
+
+        let (mut if_true, mut if_false) = stack.open_if();
+        if_true.op_add();
+        if_false.op_sub();
+        let output = stack.end_if_auto(if_true, if_false);
+        assert_eq!(output.len(), 1);
+        assert_eq!(output[0].size, 1);
+        assert_eq!(stack.get_var_name(output[0]), "(number(0x5) + number(0x2))");
+        assert_eq!(stack.get_var_from_stack(1), keep);
+
+        stack.number(7);
+        stack.op_equalverify();
+        stack.drop(keep);
+        stack.op_true();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    #[should_panic(expected = "The branches of the if need to consume and produce the same variables")]
+    fn test_end_if_auto_mismatch() {
+        let mut stack = StackTracker::new();
+        stack.number(5);
+        stack.number(1);
+        let (mut if_true, if_false) = stack.open_if();
+        if_true.op_1add();
+        if_true.number(1);
+        stack.end_if_auto(if_true, if_false);
+    }
+
     #[test]
     fn test_debug_visualization() {
         let mut stack = StackTracker::new();
@@ -1537,6 +5493,124 @@ mod tests {
     }
 
 
+    fn check_sort(values: &[u32]) {
+        let mut stack = StackTracker::new();
+        for v in values {
+            stack.number(*v);
+        }
+        let sorted = stack.sort_top(values.len() as u32);
+        assert_eq!(sorted.len(), values.len());
+
+        let mut expected = values.to_vec();
+        expected.sort();
+        for v in expected.iter().rev() {
+            stack.number(*v);
+            stack.op_equalverify();
+        }
+        stack.op_true();
+        assert!(stack.run().success, "failed to sort {:?}", values);
+    }
+
+    #[test]
+    fn test_sort_top() {
+        check_sort(&[1, 2, 3]);
+        check_sort(&[3, 2, 1]);
+        check_sort(&[2, 3, 1]);
+        check_sort(&[1, 3, 2]);
+        check_sort(&[2, 2, 1]);
+
+        check_sort(&[1, 2, 3, 4, 5]);
+        check_sort(&[5, 4, 3, 2, 1]);
+        check_sort(&[3, 5, 1, 4, 2]);
+        check_sort(&[4, 1, 5, 2, 3]);
+        check_sort(&[7, 7, 0, 9, 7]);
+
+        check_sort(&[8, 3, 6, 1, 7, 2, 5, 4]);
+        check_sort(&[5]);
+    }
+
+    #[test]
+    fn test_step_label() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.number(2);
+        stack.op_add();
+        stack.set_breakpoint("bp");
+        stack.number(3);
+        stack.op_equalverify();
+
+        assert_eq!(stack.step_label(0), Some("number(0x1)"));
+        assert_eq!(stack.step_label(2), Some("OP_ADD()"));
+        assert_eq!(stack.step_label(3), Some("breakpoint(bp)"));
+        assert_eq!(stack.step_label(5), Some("OP_EQUALVERIFY()"));
+        assert_eq!(stack.step_label(6), None);
+
+        let mut stack = StackTracker::new_no_debug();
+        stack.number(1);
+        assert_eq!(stack.step_label(0), None);
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.comment("first");
+        stack.number(2);
+        stack.set_breakpoint("bp");
+        stack.number(3);
+        stack.comment("second");
+        stack.op_add();
+        stack.op_add();
+
+        assert_eq!(stack.get_section(0), None);
+        assert_eq!(stack.get_section(1), Some("first"));
+        assert_eq!(stack.get_section(4), Some("first"));
+        assert_eq!(stack.get_section(7), Some("second"));
+
+        assert_eq!(stack.get_next_comment(0), Some((1, "first".to_string())));
+        assert_eq!(stack.get_next_comment(1), Some((5, "second".to_string())));
+        assert_eq!(stack.get_next_comment(5), None);
+        assert_eq!(stack.get_prev_comment(7), Some((5, "second".to_string())));
+        assert_eq!(stack.get_prev_comment(5), Some((1, "first".to_string())));
+        assert_eq!(stack.get_prev_comment(1), None);
+
+        assert_eq!(stack.get_next_breakpoint(0), Some((3, "bp".to_string())));
+    }
+
+    #[test]
+    fn test_get_script_range() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.number(2);
+        let from = stack.get_script_len();
+        stack.op_add();
+        stack.number(3);
+        let to = stack.get_script_len() - 1;
+        stack.op_equal();
+
+        let expected = script! {
+            OP_ADD
+            3
+        };
+        assert_eq!(stack.get_script_range(from, to), expected);
+        assert_eq!(stack.get_script_range(0, stack.get_script_len() - 1), stack.get_script());
+    }
+
+    #[test]
+    fn test_display() {
+        let mut stack = StackTracker::new();
+        stack.number(1);
+        stack.number_u32(2);
+        stack.number(3);
+        stack.to_altstack();
+
+        let display = stack.to_string();
+        assert!(display.contains("steps: 4"));
+        assert!(display.contains("stack: 9 (2 vars)"));
+        assert!(display.contains("altstack: 1 (1 vars)"));
+        assert!(display.contains("top: [number_u32(0x2), number(0x1)]"));
+    }
+
     #[test]
     fn test_hex_as_nibble() {
         let mut stack = StackTracker::new();
@@ -1548,6 +5622,228 @@ mod tests {
         assert!(stack.run().success);
     }
 
+    #[test]
+    fn test_size_verify() {
+        let mut stack = StackTracker::new();
+        stack.hexstr("4bf5122f344554c53bde2ebb8cd2b7e3d1600ad631c385a5d7cce23c7785459a");
+        stack.op_size_verify(32);
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new();
+        stack.hexstr("4bf5122f344554c53bde2ebb8cd2b7e3d1600ad631c385a5d7cce23c7785459a");
+        stack.op_size_verify(31);
+        let ret = stack.run();
+        assert!(ret.error);
+        assert!(!ret.success);
+
+        let mut stack = StackTracker::new();
+        stack.hexstr("01020304");
+        stack.op_size();
+        stack.number(4);
+        stack.op_equalverify();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_op_return() {
+        let mut stack = StackTracker::new();
+        stack.op_return(&[1, 2, 3, 4]);
+        assert_eq!(stack.get_script().to_asm_string(), "OP_RETURN OP_PUSHBYTES_4 01020304");
+
+        let ret = stack.run();
+        assert!(ret.error);
+        assert!(ret.error_msg.contains("OpReturn"));
+        assert!(!ret.success);
+    }
+
+    #[test]
+    fn test_checksigadd_threshold() {
+        let pubkeys = vec![vec![1u8; 32], vec![2u8; 32], vec![3u8; 32]];
+
+        let mut stack = StackTracker::new();
+        for _ in 0..3 {
+            stack.number(0);
+        }
+        let result = stack.checksigadd_threshold(&pubkeys, 2);
+        assert_eq!(stack.data.stack.len(), 1);
+        assert_eq!(stack.get_var_from_stack(0), result);
+        assert_eq!(stack.get_var_name(result), "checksigadd(2-of-3)");
+
+        // without signatures the threshold is not reached
+        stack.op_not();
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new();
+        for _ in 0..3 {
+            stack.number(0);
+        }
+        stack.checksigadd_threshold(&pubkeys, 0);
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    fn test_run_with_dummy_sig() {
+        let mut stack = StackTracker::new();
+        stack.var(1, script!{ { dummy_signature() } }, "sig");
+        stack.var(1, script!{ { dummy_pubkey().serialize().to_vec() } }, "pubkey");
+        stack.op_checksig();
+        assert!(stack.run_with_dummy_sig().success);
+        assert!(!stack.run().success);
+
+        let mut stack = StackTracker::new();
+        stack.var(1, script!{ { dummy_signature() } }, "sig");
+        stack.var(1, script!{ { vec![2u8; 32] } }, "pubkey");
+        stack.op_checksigverify();
+        stack.op_true();
+        assert!(!stack.run_with_dummy_sig().success);
+    }
+
+    #[test]
+    fn test_p2pk() {
+        let pubkey = dummy_pubkey().serialize();
+        let mut stack = StackTracker::p2pk(&pubkey);
+        assert_eq!(stack.get_script().to_asm_string(), format!("OP_PUSHBYTES_32 {} OP_CHECKSIGVERIFY OP_PUSHNUM_1", hex::encode(pubkey)));
+        stack.witness = vec![dummy_signature()];
+        assert!(stack.run_with_dummy_sig().success);
+        assert!(!stack.run().success);
+    }
+
+    #[test]
+    fn test_p2pkh() {
+        let pubkey = dummy_pubkey().serialize();
+        let hash = hash160::Hash::hash(&pubkey).to_byte_array();
+        let mut stack = StackTracker::p2pkh(&hash);
+        assert_eq!(stack.get_script().to_asm_string(), format!("OP_DUP OP_HASH160 OP_PUSHBYTES_20 {} OP_EQUALVERIFY OP_CHECKSIG", hex::encode(hash)));
+        stack.witness = vec![dummy_signature(), pubkey.to_vec()];
+        assert!(stack.run_with_dummy_sig().success);
+
+        stack.witness = vec![dummy_signature(), vec![2u8; 32]];
+        assert!(!stack.run_with_dummy_sig().success);
+    }
+
+    #[test]
+    fn test_htlc() {
+        let preimage = vec![7u8; 32];
+        let hash = hash160::Hash::hash(&preimage).to_byte_array();
+        let mut stack = StackTracker::new();
+        let signature = stack.define(1, "signature");
+        stack.define(1, "preimage");
+        stack.htlc(&hash);
+        assert_eq!(stack.get_var_from_stack(0), signature);
+        assert_eq!(stack.get_script().to_asm_string(), format!("OP_SIZE OP_PUSHBYTES_1 20 OP_EQUALVERIFY OP_HASH160 OP_PUSHBYTES_20 {} OP_EQUALVERIFY", hex::encode(hash)));
+        stack.drop(signature);
+        stack.op_true();
+
+        stack.witness = vec![dummy_signature(), preimage];
+        assert!(stack.run().success);
+
+        // right hash but wrong size
+        let short = vec![7u8; 31];
+        let mut stack = StackTracker::new();
+        stack.define(1, "preimage");
+        stack.htlc(&hash160::Hash::hash(&short).to_byte_array());
+        stack.op_true();
+        stack.witness = vec![short];
+        assert!(!stack.run().success);
+    }
+
+    #[test]
+    fn test_run_with_trace() {
+        let mut stack = StackTracker::new();
+        stack.number(10);
+        stack.custom(script!{ OP_CSV OP_DROP }, 1, false, 0, "csv");
+        stack.op_true();
+
+        let mut template = dummy_sig_tx_template();
+        template.tx.input[0].sequence = Sequence::from_height(10);
+        let trace = stack.run_with_trace(template, ExecCtx::Tapscript, Options::default());
+        assert_eq!(trace.len(), 4);
+        let csv = trace.iter().position(|s| s.last_opcode == "OP_CSV").unwrap();
+        assert_eq!(csv, 1);
+        assert!(!trace[csv].error);
+        assert_eq!(trace[csv].stack, vec!["a"]);
+        assert!(trace.last().unwrap().success);
+        assert!(!trace[..3].iter().any(|s| s.success || s.error));
+    }
+
+    fn no_debug_gadget(stack: &mut StackTracker) {
+        let mut x = stack.number_u32(0x12345678);
+        let y = stack.number(5);
+        stack.copy_var(y);
+        stack.op_add();
+        stack.number(10);
+        stack.op_equalverify();
+        let mut z = stack.number_u32(0x12345678);
+        stack.equals(&mut x, true, &mut z, true);
+        stack.op_true();
+    }
+
+    #[test]
+    fn test_no_debug() {
+        let mut stack = StackTracker::new();
+        no_debug_gadget(&mut stack);
+
+        let mut fast = StackTracker::new_no_debug();
+        no_debug_gadget(&mut fast);
+
+        assert_eq!(stack.get_script(), fast.get_script());
+        assert!(fast.data.redo_log.is_empty());
+        assert!(fast.history.is_empty());
+        assert!(fast.run().success);
+    }
+
+    #[test]
+    fn test_assert_preimage() {
+        let preimage = "0102030405060708090a0b0c0d0e0f10";
+        let bytes = Vec::from_hex(preimage).unwrap();
+        let hash160_digest = hash160::Hash::hash(&bytes).to_byte_array();
+        let sha256_digest = sha256::Hash::hash(&bytes).to_byte_array();
+
+        let mut stack = StackTracker::new();
+        stack.hexstr(preimage);
+        stack.assert_hash160_preimage(&hash160_digest);
+        stack.hexstr(preimage);
+        stack.assert_sha256_preimage(&sha256_digest);
+        assert!(stack.data.stack.is_empty());
+        stack.op_true();
+        assert!(stack.run().success);
+
+        let mut stack = StackTracker::new();
+        stack.hexstr("0102");
+        stack.assert_hash160_preimage(&hash160_digest);
+        stack.op_true();
+        assert!(stack.run().error);
+
+        let mut stack = StackTracker::new();
+        stack.hexstr("0102");
+        stack.assert_sha256_preimage(&sha256_digest);
+        stack.op_true();
+        assert!(stack.run().error);
+    }
+
+    #[test]
+    fn test_sha256_var() {
+        let mut stack = StackTracker::new();
+        let bottom = stack.number(5);
+        let data = stack.hexstr("0102030405");
+        let top = stack.number(7);
+        let digest = stack.op_sha256_var(data);
+        assert_eq!(stack.data.stack, vec![bottom, top, digest]);
+        assert_eq!(stack.get_var_name(digest), "sha256(hexdata)");
+        stack.hexstr(&sha256_hex(&[1, 2, 3, 4, 5]));
+        stack.op_equalverify();
+        stack.op_drop();
+        assert!(stack.run().success);
+    }
+
+    #[test]
+    #[should_panic(expected = "hashes a single element")]
+    fn test_sha256_var_size() {
+        let mut stack = StackTracker::new();
+        let data = stack.number_u32(1);
+        stack.op_sha256_var(data);
+    }
+
     #[test]
     fn test_hash_functions() {
         let mut stack = StackTracker::new();
@@ -1557,11 +5853,34 @@ mod tests {
         stack.op_hash256();
         stack.op_hash160();
         stack.op_ripemd160();
-        stack.hexstr("aa72add4303b07bc32852fee998493b48d7dd33d");
+
+        let digest = sha256::Hash::hash(&[1]).to_byte_array();
+        let digest = sha256d::Hash::hash(&digest).to_byte_array();
+        let digest = hash160::Hash::hash(&digest).to_byte_array();
+        let digest = ripemd160::Hash::hash(&digest).to_byte_array();
+        assert_eq!(hex::encode(digest), "aa72add4303b07bc32852fee998493b48d7dd33d");
+
+        stack.hexstr(&hex::encode(digest));
         stack.debug();
         stack.op_equal();
         assert!(stack.run().success);
 
+        // each gadget on its own against the helpers
+        type HashGadget = fn(&mut StackTracker) -> StackVariable;
+        let cases: [(HashGadget, String); 4] = [
+            (StackTracker::op_sha256, sha256_hex(&[1])),
+            (StackTracker::op_hash256, hash256_hex(&[1])),
+            (StackTracker::op_hash160, hash160_hex(&[1])),
+            (StackTracker::op_ripemd160, ripemd160_hex(&[1])),
+        ];
+        for (op, expected) in cases {
+            let mut stack = StackTracker::new();
+            stack.number(1);
+            op(&mut stack);
+            stack.hexstr(&expected);
+            stack.op_equal();
+            assert!(stack.run().success);
+        }
     }
 
 
@@ -0,0 +1,22 @@
+use alloc::string::String;
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d, Hash};
+
+// expected digests (as hex, ready for StackTracker::hexstr) to compare against the hash gadgets
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(sha256::Hash::hash(data).to_byte_array())
+}
+
+// ripemd160(sha256(data)), as OP_HASH160
+pub fn hash160_hex(data: &[u8]) -> String {
+    hex::encode(hash160::Hash::hash(data).to_byte_array())
+}
+
+// sha256(sha256(data)), as OP_HASH256
+pub fn hash256_hex(data: &[u8]) -> String {
+    hex::encode(sha256d::Hash::hash(data).to_byte_array())
+}
+
+pub fn ripemd160_hex(data: &[u8]) -> String {
+    hex::encode(ripemd160::Hash::hash(data).to_byte_array())
+}
\ No newline at end of file